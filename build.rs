@@ -0,0 +1,84 @@
+use std::{env, fs, path::PathBuf};
+
+#[cfg(feature = "embed")]
+use {
+    flate2::{write::GzEncoder, Compression},
+    std::{io::Write, path::Path, time::UNIX_EPOCH},
+};
+
+/// Embeds the directory tree named by `ZEST_EMBED_DIR` when the `embed`
+/// feature is enabled, gzipping every file into `OUT_DIR`.
+fn main() {
+    println!("cargo:rerun-if-env-changed=ZEST_EMBED_DIR");
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    #[allow(unused_mut)]
+    let mut table = String::new();
+
+    #[cfg(feature = "embed")]
+    if let Some(dir) = env::var_os("ZEST_EMBED_DIR") {
+        let dir = PathBuf::from(dir);
+        println!("cargo:rerun-if-changed={}", dir.display());
+
+        let mut files = vec![];
+        walk(&dir, "", &mut files);
+        files.sort();
+
+        let embed_dir = out_dir.join("embed");
+        fs::create_dir_all(&embed_dir).unwrap();
+        for (i, (name, path)) in files.into_iter().enumerate() {
+            let content = fs::read(&path).unwrap();
+            let mtime = fs::metadata(&path)
+                .and_then(|meta| meta.modified())
+                .ok()
+                .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |mtime| mtime.as_secs());
+
+            let mut encoder = GzEncoder::new(vec![], Compression::best());
+            encoder.write_all(&content).unwrap();
+            let gz = embed_dir.join(format!("{}.gz", i));
+            fs::write(&gz, encoder.finish().unwrap()).unwrap();
+
+            table.push_str(&format!(
+                "    EmbeddedFile {{ path: {:?}, data: include_bytes!({:?}), size: {}, mtime: {}, etag: \"\\\"{:016x}\\\"\" }},\n",
+                name,
+                gz.display().to_string(),
+                content.len(),
+                mtime,
+                fnv1a(&content),
+            ));
+        }
+    }
+
+    fs::write(
+        out_dir.join("embedded.rs"),
+        format!(
+            "pub static EMBEDDED_FILES: &[EmbeddedFile] = &[\n{}];\n",
+            table
+        ),
+    )
+    .unwrap();
+}
+
+#[cfg(feature = "embed")]
+fn walk(dir: &Path, prefix: &str, files: &mut Vec<(String, PathBuf)>) {
+    for entry in fs::read_dir(dir).unwrap().flatten() {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let name = format!("{}{}", prefix, name);
+        let path = entry.path();
+        if path.is_dir() {
+            walk(&path, &format!("{}/", name), files);
+        } else if path.is_file() {
+            files.push((name, path));
+        }
+    }
+}
+
+#[cfg(feature = "embed")]
+fn fnv1a(content: &[u8]) -> u64 {
+    content.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
@@ -0,0 +1,741 @@
+use crate::{
+    config::CONFIG,
+    route::{location_config, normalize_location},
+    storage::{
+        dir_prefix, is_site_rules, location_storage, resolve_root, Metadata, Storage, StorageEntry,
+        StorageReader,
+    },
+};
+use chrono::{DateTime, Datelike, Local, NaiveDate, Timelike};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use futures::{future::BoxFuture, FutureExt};
+use lazy_static::lazy_static;
+use mime::Mime;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env::temp_dir,
+    fs::{remove_file, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    os::unix::fs::{FileExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    pin::Pin,
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+    time::SystemTime,
+};
+use tokio::{
+    fs::metadata,
+    io::{AsyncRead, ReadBuf},
+    sync::mpsc::{channel, Receiver, Sender},
+    task::spawn_blocking,
+};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+#[cfg(feature = "log")]
+use log::logger;
+
+/// Bytes gathered before a chunk is handed to the connection.
+const ARCHIVE_CHUNK: usize = 64 * 1024;
+
+/// Most bytes a compressed `archive_root` may unpack to.
+const MAX_UNPACKED_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+
+lazy_static! {
+    /// Archives opened for `archive_root` locations, by path. Cleared on
+    /// SIGHUP so that they are reopened.
+    pub static ref MOUNTED_ARCHIVES: Mutex<HashMap<PathBuf, Arc<MountedArchive>>> =
+        Mutex::new(HashMap::new());
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ArchiveFormat {
+    Zip,
+    TarGz,
+}
+
+impl ArchiveFormat {
+    /// Parses the value of an `?archive=` query.
+    pub fn new(format: &str) -> Option<Self> {
+        match format {
+            "zip" => Some(ArchiveFormat::Zip),
+            "tar.gz" | "tgz" => Some(ArchiveFormat::TarGz),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn mime(&self) -> Mime {
+        match self {
+            ArchiveFormat::Zip => "application/zip".parse().unwrap(),
+            ArchiveFormat::TarGz => "application/gzip".parse().unwrap(),
+        }
+    }
+
+    #[inline]
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::TarGz => "tar.gz",
+        }
+    }
+}
+
+/// A file or directory to be written into an archive.
+pub struct ArchiveEntry {
+    pub name: String,
+    pub path: PathBuf,
+    pub dir: bool,
+    pub mtime: SystemTime,
+}
+
+/// Collects everything below the directory at `location` that would be
+/// served on its own, named relative to the directory's parent.
+///
+/// Entries are resolved like requests, so hidden, escaping and disallowed
+/// symlinked paths are left out, as are names matched by `index_hide` and
+/// the site rule files.
+/// Returns 413 once the location's entry or size limit is exceeded.
+pub async fn archive_entries(location: &str) -> Result<Vec<ArchiveEntry>, i32> {
+    let limits = location_config(location).unwrap_or_default();
+    let max_entries = limits.archive_max_entries.unwrap_or(10000);
+    let max_size = limits
+        .archive_max_size
+        .map_or(1024 * 1024 * 1024 /* 1 GB */, |size| size.0);
+
+    let location = location.trim_matches('/').to_owned();
+    let root = resolve_root(&location)?;
+    let base = archive_name(&location);
+
+    let mut entries: Vec<ArchiveEntry> = vec![];
+    let mut visited: HashSet<PathBuf> = HashSet::from([root.clone()]);
+    let mut dirs = vec![(location, base, root)];
+    let mut size: u64 = 0;
+
+    while let Some((location, name, path)) = dirs.pop() {
+        let hide = location_config(&location)
+            .and_then(|l| l.index_hide)
+            .unwrap_or_default();
+        let mtime = metadata(&path)
+            .await
+            .and_then(|meta| meta.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        entries.push(ArchiveEntry {
+            name: name.clone(),
+            path: path.clone(),
+            dir: true,
+            mtime,
+        });
+
+        let (storage, dir_path) = location_storage(&location).await?;
+        let children = storage.read_dir(&dir_path).await.map_err(|_| 403)?;
+        for StorageEntry {
+            name: child_name, ..
+        } in children
+        {
+            if hide.is_match(&child_name) {
+                continue;
+            }
+
+            let child_location = if location.is_empty() {
+                child_name.clone()
+            } else {
+                format!("{}/{}", location, child_name)
+            };
+            if is_site_rules(&child_location) {
+                continue;
+            }
+            let Ok(child_path) = resolve_root(&child_location) else {
+                continue;
+            };
+            let Ok(meta) = metadata(&child_path).await else {
+                continue;
+            };
+            let child_name = format!("{}/{}", name, child_name);
+
+            if entries.len() + dirs.len() >= max_entries {
+                return Err(413);
+            }
+            if meta.is_dir() {
+                if visited.insert(child_path.clone()) {
+                    dirs.push((child_location, child_name, child_path));
+                }
+            } else {
+                size = size.saturating_add(meta.len());
+                if size > max_size {
+                    return Err(413);
+                }
+                entries.push(ArchiveEntry {
+                    name: child_name,
+                    path: child_path,
+                    dir: false,
+                    mtime: meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                });
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Name of the archive and of its top-level directory, without control
+/// characters, quotes or backslashes.
+pub fn archive_name(location: &str) -> String {
+    let name: String = location
+        .trim_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control() && !matches!(c, '"' | '\\'))
+        .collect();
+    if name.is_empty() {
+        "root".to_owned()
+    } else {
+        name
+    }
+}
+
+/// `Content-Disposition` for the archive of `location`: an ASCII `filename`
+/// for old clients and the full name in `filename*` (RFC 6266).
+pub fn archive_disposition(location: &str, format: ArchiveFormat) -> String {
+    let name = format!("{}.{}", archive_name(location), format.extension());
+    let ascii: String = name
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect();
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        ascii,
+        urlencoding::encode(&name)
+    )
+}
+
+/// Writes the archive on a blocking thread, yielding it in chunks as it is
+/// compressed so that nothing is buffered on disk.
+pub fn archive_stream(
+    format: ArchiveFormat,
+    entries: Vec<ArchiveEntry>,
+) -> Receiver<io::Result<Vec<u8>>> {
+    let (tx, rx) = channel(4);
+
+    spawn_blocking(move || {
+        let mut writer = ChannelWriter {
+            tx: tx.clone(),
+            buffer: Vec::with_capacity(ARCHIVE_CHUNK),
+        };
+        let result = match format {
+            ArchiveFormat::Zip => write_zip(&mut writer, &entries),
+            ArchiveFormat::TarGz => write_tar_gz(&mut writer, &entries),
+        }
+        .and_then(|_| writer.flush());
+
+        if let Err(e) = result {
+            let _ = tx.blocking_send(Err(e));
+        }
+    });
+
+    rx
+}
+
+fn write_zip(writer: &mut ChannelWriter, entries: &[ArchiveEntry]) -> io::Result<()> {
+    let mut zip = ZipWriter::new_stream(writer);
+
+    for entry in entries {
+        let mut options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .last_modified_time(zip_time(entry.mtime));
+
+        if entry.dir {
+            // `add_directory` flags a data descriptor it never writes when
+            // streaming, which unzip rejects, so write an empty entry instead
+            zip.start_file(
+                format!("{}/", entry.name),
+                options
+                    .compression_method(CompressionMethod::Stored)
+                    .unix_permissions(0o40755),
+            )?;
+        } else {
+            let mut file = File::open(&entry.path)?;
+            options = options.large_file(file.metadata()?.len() >= u32::MAX as u64);
+            zip.start_file(&entry.name, options)?;
+            io::copy(&mut file, &mut zip)?;
+        }
+    }
+
+    zip.finish()?;
+    Ok(())
+}
+
+fn write_tar_gz(writer: &mut ChannelWriter, entries: &[ArchiveEntry]) -> io::Result<()> {
+    let mut tar = tar::Builder::new(GzEncoder::new(writer, Compression::default()));
+
+    for entry in entries {
+        if entry.dir {
+            tar.append_dir(&entry.name, &entry.path)?;
+        } else {
+            tar.append_path_with_name(&entry.path, &entry.name)?;
+        }
+    }
+
+    tar.into_inner()?.finish()?;
+    Ok(())
+}
+
+#[inline]
+fn zip_time(mtime: SystemTime) -> zip::DateTime {
+    let mtime: DateTime<Local> = mtime.into();
+    zip::DateTime::from_date_and_time(
+        mtime.year().clamp(1980, 2107) as u16,
+        mtime.month() as u8,
+        mtime.day() as u8,
+        mtime.hour() as u8,
+        mtime.minute() as u8,
+        mtime.second() as u8,
+    )
+    .unwrap_or_default()
+}
+
+/// Forwards everything written to it over a channel in `ARCHIVE_CHUNK`
+/// sized pieces, failing once the connection has gone away.
+struct ChannelWriter {
+    tx: Sender<io::Result<Vec<u8>>>,
+    buffer: Vec<u8>,
+}
+
+impl ChannelWriter {
+    fn send(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(ARCHIVE_CHUNK));
+        self.tx
+            .blocking_send(Ok(chunk))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= ARCHIVE_CHUNK {
+            self.send()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+/// Where the bytes of an archive member are kept.
+enum MemberData {
+    Dir,
+    Zip(usize),
+    Tar(u64),
+}
+
+struct Member {
+    size: u64,
+    mtime: i64,
+    data: MemberData,
+}
+
+/// A file read at positions of its own by each clone, so that members can
+/// be read concurrently without reopening the archive.
+#[derive(Clone)]
+struct SharedFile {
+    file: Arc<File>,
+    pos: u64,
+}
+
+impl Read for SharedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read_at(buf, self.pos)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for SharedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.file.metadata()?.len().checked_add_signed(offset),
+        };
+        self.pos = pos.ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        Ok(self.pos)
+    }
+}
+
+/// A file member to copy out of the archive on a blocking thread.
+enum MemberSource {
+    Zip(ZipArchive<SharedFile>, usize),
+    Tar(SharedFile, u64),
+}
+
+impl MemberSource {
+    /// Copies the bytes of `range` to `writer`, decompressing zip members
+    /// from their start.
+    fn copy_to<W: Write>(self, range: Range<u64>, writer: &mut W) -> io::Result<u64> {
+        let len = range.end.saturating_sub(range.start);
+        match self {
+            MemberSource::Zip(mut zip, i) => {
+                let mut file = zip.by_index(i)?;
+                io::copy(&mut (&mut file).take(range.start), &mut io::sink())?;
+                io::copy(&mut file.take(len), writer)
+            }
+            MemberSource::Tar(mut file, offset) => {
+                file.seek(SeekFrom::Start(offset + range.start))?;
+                io::copy(&mut file.take(len), writer)
+            }
+        }
+    }
+}
+
+/// Reads the chunks sent by a `ChannelWriter`.
+struct ChannelReader {
+    rx: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl AsyncRead for ChannelReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.pos == this.chunk.len() {
+            match ready!(this.rx.poll_recv(cx)) {
+                Some(Ok(chunk)) => (this.chunk, this.pos) = (chunk, 0),
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let len = buf.remaining().min(this.chunk.len() - this.pos);
+        buf.put_slice(&this.chunk[this.pos..this.pos + len]);
+        this.pos += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A zip or tar archive served as a read-only tree, indexed by member path
+/// relative to the archive root, which is the empty path.
+pub struct MountedArchive {
+    file: SharedFile,
+    zip: Option<ZipArchive<SharedFile>>,
+    members: BTreeMap<String, Member>,
+}
+
+impl MountedArchive {
+    /// Indexes the archive at `path`, which has to end in `.zip`, `.tar`,
+    /// `.tar.gz` or `.tgz`. Compressed tarballs are unpacked to an unlinked
+    /// temporary file first since they cannot be seeked in.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let name = path.to_string_lossy().to_lowercase();
+        let mtime = path
+            .metadata()?
+            .modified()?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |mtime| mtime.as_secs() as i64);
+        let file = if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            unpack(path)?
+        } else {
+            File::open(path)?
+        };
+        let mut archive = MountedArchive {
+            file: SharedFile {
+                file: Arc::new(file),
+                pos: 0,
+            },
+            zip: None,
+            members: BTreeMap::from([(
+                String::new(),
+                Member {
+                    size: 0,
+                    mtime,
+                    data: MemberData::Dir,
+                },
+            )]),
+        };
+
+        if name.ends_with(".zip") {
+            let mut zip = ZipArchive::new(archive.file.clone())?;
+            for i in 0..zip.len() {
+                let file = zip.by_index_raw(i)?;
+                if file.is_symlink() {
+                    continue;
+                }
+                let mtime = file.last_modified().map_or(0, |mtime| {
+                    NaiveDate::from_ymd_opt(
+                        mtime.year() as i32,
+                        mtime.month() as u32,
+                        mtime.day() as u32,
+                    )
+                    .and_then(|date| {
+                        date.and_hms_opt(
+                            mtime.hour() as u32,
+                            mtime.minute() as u32,
+                            mtime.second() as u32,
+                        )
+                    })
+                    .and_then(|mtime| mtime.and_local_timezone(Local).single())
+                    .map_or(0, |mtime| mtime.timestamp())
+                });
+                let member = if file.is_dir() {
+                    Member {
+                        size: 0,
+                        mtime,
+                        data: MemberData::Dir,
+                    }
+                } else {
+                    Member {
+                        size: file.size(),
+                        mtime,
+                        data: MemberData::Zip(i),
+                    }
+                };
+                let name = file.name().to_owned();
+                drop(file);
+                archive.insert(&name, member);
+            }
+            archive.zip = Some(zip);
+        } else if name.ends_with(".tar") || name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            archive.index_tar()?;
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unsupported archive format",
+            ));
+        }
+
+        Ok(archive)
+    }
+
+    fn index_tar(&mut self) -> io::Result<()> {
+        let mut tar = tar::Archive::new(self.file.clone());
+        for entry in tar.entries_with_seek()? {
+            let entry = entry?;
+            let kind = entry.header().entry_type();
+            let Some(name) = entry.path()?.to_str().map(|name| name.to_owned()) else {
+                continue;
+            };
+            let mtime = entry.header().mtime().unwrap_or_default() as i64;
+
+            let member = if kind.is_dir() {
+                Member {
+                    size: 0,
+                    mtime,
+                    data: MemberData::Dir,
+                }
+            } else if kind.is_file() || kind.is_contiguous() {
+                Member {
+                    size: entry.size(),
+                    mtime,
+                    data: MemberData::Tar(entry.raw_file_position()),
+                }
+            } else {
+                continue;
+            };
+            self.insert(&name, member);
+        }
+        Ok(())
+    }
+
+    /// Adds a member under its normalized name, along with any parent
+    /// directory the archive leaves out. Names escaping the root are dropped.
+    fn insert(&mut self, name: &str, member: Member) {
+        let Ok(name) = normalize_location(name) else {
+            return;
+        };
+        if name.is_empty() {
+            return;
+        }
+
+        let mut parent = name.as_str();
+        while let Some((dir, _)) = parent.rsplit_once('/') {
+            parent = dir;
+            self.members.entry(dir.to_owned()).or_insert(Member {
+                size: 0,
+                mtime: member.mtime,
+                data: MemberData::Dir,
+            });
+        }
+        self.members.insert(name, member);
+    }
+
+    /// The size and source of a file member.
+    fn source(&self, member: &str) -> io::Result<(u64, MemberSource)> {
+        let not_found = || io::Error::from(io::ErrorKind::NotFound);
+        let entry = self.members.get(member).ok_or_else(not_found)?;
+
+        let source = match &entry.data {
+            MemberData::Dir => return Err(io::Error::from(io::ErrorKind::IsADirectory)),
+            MemberData::Zip(i) => {
+                MemberSource::Zip(self.zip.as_ref().ok_or_else(not_found)?.clone(), *i)
+            }
+            MemberData::Tar(offset) => MemberSource::Tar(self.file.clone(), *offset),
+        };
+        Ok((entry.size, source))
+    }
+
+    #[inline]
+    fn metadata(member: &Member) -> Metadata {
+        Metadata {
+            dir: matches!(member.data, MemberData::Dir),
+            size: member.size,
+            mtime: member.mtime,
+            ..Default::default()
+        }
+    }
+}
+
+impl Storage for MountedArchive {
+    fn open<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<StorageReader>> {
+        async move {
+            let (size, source) = self.source(path)?;
+            let (tx, rx) = channel(4);
+            spawn_blocking(move || {
+                let mut writer = ChannelWriter {
+                    tx: tx.clone(),
+                    buffer: Vec::with_capacity(ARCHIVE_CHUNK),
+                };
+                let result = source
+                    .copy_to(0..size, &mut writer)
+                    .and_then(|_| writer.flush());
+                if let Err(e) = result {
+                    let _ = tx.blocking_send(Err(e));
+                }
+            });
+            Ok(Box::new(ChannelReader {
+                rx,
+                chunk: vec![],
+                pos: 0,
+            }) as StorageReader)
+        }
+        .boxed()
+    }
+
+    fn stat<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<Metadata>> {
+        async move {
+            self.members
+                .get(path)
+                .map(Self::metadata)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<Vec<StorageEntry>>> {
+        async move {
+            if !self.stat(path).await?.dir {
+                return Err(io::Error::from(io::ErrorKind::NotADirectory));
+            }
+
+            let prefix = dir_prefix(path);
+            Ok(self
+                .members
+                .range(prefix.clone()..)
+                .take_while(|(name, _)| name.starts_with(&prefix))
+                .filter(|(name, _)| !name.is_empty() && !name[prefix.len()..].contains('/'))
+                .map(|(name, member)| StorageEntry {
+                    name: name[prefix.len()..].to_owned(),
+                    meta: Self::metadata(member),
+                })
+                .collect())
+        }
+        .boxed()
+    }
+
+    fn read_range<'a>(
+        &'a self,
+        path: &'a str,
+        range: Range<u64>,
+    ) -> BoxFuture<'a, io::Result<Vec<u8>>> {
+        async move {
+            let (size, source) = self.source(path)?;
+            let end = range.end.min(size);
+            let range = range.start.min(end)..end;
+            spawn_blocking(move || {
+                let mut content = Vec::with_capacity((range.end - range.start) as usize);
+                source.copy_to(range, &mut content)?;
+                Ok(content)
+            })
+            .await?
+        }
+        .boxed()
+    }
+}
+
+/// Decompresses the tarball at `path` to a temporary file unlinked at once,
+/// failing when it unpacks to more than `MAX_UNPACKED_SIZE`.
+fn unpack(path: &Path) -> io::Result<File> {
+    static UNPACKED: AtomicU64 = AtomicU64::new(0);
+    let temp = temp_dir().join(format!(
+        "zest-{}-{}.tar",
+        process::id(),
+        UNPACKED.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&temp)?;
+    remove_file(&temp)?;
+
+    let mut tarball = GzDecoder::new(File::open(path)?).take(MAX_UNPACKED_SIZE + 1);
+    if io::copy(&mut tarball, &mut file)? > MAX_UNPACKED_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "archive unpacks to more than 4 GiB",
+        ));
+    }
+    Ok(file)
+}
+
+/// The archive at `archive_root`, relative to the first root layer having
+/// it, opened on first use and kept until SIGHUP. Answers 500 when it cannot
+/// be read.
+pub async fn mounted_archive(archive_root: &Path) -> Result<Arc<MountedArchive>, i32> {
+    let config = CONFIG.load();
+    let path = config
+        .server
+        .root
+        .layers()
+        .iter()
+        .map(|root| root.join(archive_root))
+        .find(|path| path.exists())
+        .unwrap_or_else(|| config.server.root.join(archive_root));
+    let cached = MOUNTED_ARCHIVES.lock().unwrap().get(&path).cloned();
+    match cached {
+        Some(archive) => Ok(archive),
+        None => {
+            let _path = path.clone();
+            match spawn_blocking(move || MountedArchive::open(&_path)).await {
+                Ok(Ok(archive)) => {
+                    let archive = Arc::new(archive);
+                    MOUNTED_ARCHIVES
+                        .lock()
+                        .unwrap()
+                        .insert(path, Arc::clone(&archive));
+                    Ok(archive)
+                }
+                Ok(Err(_e)) => {
+                    #[cfg(feature = "log")]
+                    error!("failed to open archive {}: {}", path.display(), _e);
+                    Err(500)
+                }
+                Err(_) => Err(500),
+            }
+        }
+    }
+}
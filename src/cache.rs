@@ -0,0 +1,93 @@
+use lru::{Iter, LruCache};
+
+/// Values whose size in memory counts against a `WeightedCache` budget.
+pub trait Weight {
+    fn weight(&self) -> usize;
+}
+
+impl Weight for Vec<u8> {
+    fn weight(&self) -> usize {
+        self.len()
+    }
+}
+
+impl Weight for String {
+    fn weight(&self) -> usize {
+        self.len()
+    }
+}
+
+/// An LRU cache bounded by the total bytes of its keys and values rather than
+/// by how many entries it holds.
+pub struct WeightedCache<V> {
+    cache: LruCache<String, V>,
+    used: usize,
+    budget: usize,
+}
+
+impl<V: Weight> WeightedCache<V> {
+    pub fn new(budget: usize) -> Self {
+        WeightedCache {
+            cache: LruCache::unbounded(),
+            used: 0,
+            budget,
+        }
+    }
+
+    #[inline]
+    fn weight(key: &str, value: &V) -> usize {
+        key.len() + value.weight()
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<&V> {
+        self.cache.get(key)
+    }
+
+    /// Caches `value`, evicting the least recently used entries to make room.
+    /// Values larger than the whole budget are not cached.
+    pub fn put(&mut self, key: String, value: V) -> bool {
+        let weight = Self::weight(&key, &value);
+        if weight > self.budget {
+            return false;
+        }
+        self.pop(&key);
+        self.used += weight;
+        self.cache.put(key, value);
+        self.evict();
+        true
+    }
+
+    pub fn pop(&mut self, key: &str) -> Option<V> {
+        let value = self.cache.pop(key)?;
+        self.used -= Self::weight(key, &value);
+        Some(value)
+    }
+
+    pub fn clear(&mut self) {
+        self.cache.clear();
+        self.used = 0;
+    }
+
+    pub fn iter(&self) -> Iter<'_, String, V> {
+        self.cache.iter()
+    }
+
+    /// Bytes held by the cached keys and values.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    pub fn resize(&mut self, budget: usize) {
+        self.budget = budget;
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.used > self.budget {
+            let Some((key, value)) = self.cache.pop_lru() else {
+                break;
+            };
+            self.used -= Self::weight(&key, &value);
+        }
+    }
+}
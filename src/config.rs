@@ -1,17 +1,31 @@
+use crate::{
+    route::{root_relative, HidePatterns},
+    storage::resolve_root,
+};
+use anyhow::Context;
 use arc_swap::ArcSwap;
-use clap::{command, Parser};
+use clap::Parser;
 use lazy_static::lazy_static;
-use serde::{Deserialize, Serialize};
-use serde_yml::Value;
+use regex::Regex;
+use serde::{de, ser::SerializeMap, Deserialize, Deserializer, Serialize, Serializer};
 use std::{
     collections::HashMap,
     env::current_dir,
-    fs,
-    path::PathBuf,
-    sync::{Arc, Mutex},
-    time::Duration,
+    fmt, fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
+#[cfg(feature = "log")]
+use log::logger;
+
 lazy_static! {
     pub static ref CONFIG_PATH: Mutex<String> = Mutex::new("".to_owned());
     pub static ref DEFAULT_CONFIG: Config = init_config();
@@ -19,8 +33,31 @@ lazy_static! {
     pub static ref ARGS: Args = Args::parse();
     pub static ref DEFAULT_INTERVAL: Duration = Duration::from_millis(128);
     pub static ref DEFAULT_CACHE_INTERVAL: Duration = Duration::from_secs(60);
+    /// Where zest was started, which relative roots are taken from.
+    pub static ref START_DIR: PathBuf = current_dir().unwrap_or(".".into());
+    /// `.zest.yaml` lookups by the location of their directory.
+    pub static ref DIR_CONFIGS: Mutex<HashMap<String, CachedDirConfig>> =
+        Mutex::new(HashMap::new());
 }
 
+/// When a directory was looked at, the mtime of its `.zest.yaml` then, if it
+/// had one, and the parsed file, `None` when it is missing or invalid.
+pub type CachedDirConfig = (Instant, Option<SystemTime>, Option<Arc<DirConfig>>);
+
+/// Name of the per-directory override file, never served or listed.
+pub const DIR_CONFIG: &str = ".zest.yaml";
+
+/// How long a `.zest.yaml` lookup is trusted before the directory is looked
+/// at again.
+pub const DIR_CONFIG_RECHECK: Duration = Duration::from_secs(1);
+
+/// Most directories whose `.zest.yaml` lookup is remembered.
+const MAX_DIR_CONFIGS: usize = 65536;
+
+/// Set when a `.zest.yaml` appears, changes or goes away, so that listings
+/// rendered under the old settings are dropped.
+pub static DIR_CONFIGS_CHANGED: AtomicBool = AtomicBool::new(false);
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
     pub bind: BindConfig,
@@ -28,7 +65,7 @@ pub struct Config {
     pub allowlist: Option<Vec<String>>,
     pub blocklist: Option<Vec<String>>,
     pub rate_limit: Option<RateLimitConfig>,
-    pub locations: Option<HashMap<String, Value>>,
+    pub locations: Option<Locations>,
     pub logging: Option<LoggingConfig>,
 }
 
@@ -41,10 +78,15 @@ impl Default for Config {
             },
             server: ServerConfig {
                 info: "Powered by Rust".to_owned(),
-                root: current_dir().unwrap_or(".".into()),
+                root: Root::Dir(current_dir().unwrap_or(".".into())),
+                index: None,
                 error_page: Some("404.html".to_owned().into()),
+                error_pages: None,
                 interval: Some(*DEFAULT_INTERVAL),
                 cache: Some(CacheConfig::default()),
+                symlinks: None,
+                deny_hidden: None,
+                userdir: None,
             },
             allowlist: None,
             blocklist: None,
@@ -64,40 +106,579 @@ pub struct BindConfig {
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ServerConfig {
     pub info: String,
-    pub root: PathBuf,
+    pub root: Root,
+    pub index: Option<IndexFiles>,
     pub error_page: Option<PathBuf>,
+    pub error_pages: Option<HashMap<String, ErrorPage>>,
     pub interval: Option<Duration>,
     pub cache: Option<CacheConfig>,
+    pub symlinks: Option<SymlinkPolicy>,
+    pub deny_hidden: Option<bool>,
+    pub userdir: Option<UserDirConfig>,
+}
+
+/// `server.root`: a directory, or several searched in order with files in
+/// the first ones hiding those of the same name further down.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum Root {
+    Dir(PathBuf),
+    Layers(Vec<PathBuf>),
+}
+
+impl Root {
+    #[inline]
+    pub fn layers(&self) -> &[PathBuf] {
+        match self {
+            Root::Dir(dir) => std::slice::from_ref(dir),
+            Root::Layers(layers) => layers,
+        }
+    }
+}
+
+/// The first layer, where uploads and WebDAV write.
+impl Deref for Root {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        self.layers()
+            .first()
+            .map_or(Path::new("."), |dir| dir.as_path())
+    }
+}
+
+impl AsRef<Path> for Root {
+    fn as_ref(&self) -> &Path {
+        self
+    }
+}
+
+/// `index`: a file name, or several tried in order, looked up in every
+/// directory requested.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum IndexFiles {
+    File(PathBuf),
+    Files(Vec<PathBuf>),
+}
+
+impl IndexFiles {
+    #[inline]
+    pub fn files(&self) -> &[PathBuf] {
+        match self {
+            IndexFiles::File(file) => std::slice::from_ref(file),
+            IndexFiles::Files(files) => files,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SymlinkPolicy {
+    #[default]
+    Follow,
+    OwnerMatch,
+    Deny,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum ErrorPage {
+    File(PathBuf),
+    Template { template: String },
+}
+
+/// `server.userdir`: serves `/~user/` from a directory in the home of each
+/// user listed in `/etc/passwd`.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct UserDirConfig {
+    /// Defaults to `public_html`.
+    pub dir: Option<PathBuf>,
+    /// Only these users when set.
+    pub allow: Option<Vec<String>>,
+    /// Defaults to `root`.
+    pub deny: Option<Vec<String>>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct CacheConfig {
     pub interval: Option<Duration>,
-    pub index_capacity: Option<usize>,
-    pub file_capacity: Option<usize>,
-    pub file_maxsize: Option<u64>,
+    /// Memory the rendered listings may take up.
+    pub index_budget: Option<ByteSize>,
+    /// Memory the cached files may take up.
+    pub file_budget: Option<ByteSize>,
+    /// Larger files are read for every request instead of cached.
+    pub file_maxsize: Option<ByteSize>,
+    pub index_maxentries: Option<usize>,
+    /// Entry counts replaced by the byte budgets, refused when set.
+    #[serde(default, skip_serializing, deserialize_with = "removed_capacity")]
+    pub index_capacity: Option<()>,
+    #[serde(default, skip_serializing, deserialize_with = "removed_capacity")]
+    pub file_capacity: Option<()>,
+}
+
+fn removed_capacity<'de, D: Deserializer<'de>>(_: D) -> Result<Option<()>, D::Error> {
+    Err(de::Error::custom(
+        "index_capacity and file_capacity counted entries and are gone, set index_budget \
+         and file_budget in bytes instead, such as 8MiB",
+    ))
 }
 
 impl Default for CacheConfig {
     fn default() -> Self {
         CacheConfig {
             interval: Some(*DEFAULT_CACHE_INTERVAL),
-            index_capacity: Some(16),
-            file_capacity: Some(32),
-            file_maxsize: Some(32768),
+            index_budget: Some(ByteSize(8 * 1024 * 1024)),
+            file_budget: Some(ByteSize(128 * 1024 * 1024)),
+            file_maxsize: Some(ByteSize(32 * 1024 * 1024)),
+            index_maxentries: Some(10000),
+            index_capacity: None,
+            file_capacity: None,
         }
     }
 }
 
+/// A number of bytes, written as a plain number or with a unit: `B`, `K`/`KiB`,
+/// `M`/`MiB` and `G`/`GiB` count in 1024s while `KB`, `MB` and `GB` count in
+/// 1000s, so `64MiB` and `512 KB` both work.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(try_from = "ByteSizeValue")]
+pub struct ByteSize(pub u64);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ByteSizeValue {
+    Bytes(u64),
+    Text(String),
+}
+
+impl TryFrom<ByteSizeValue> for ByteSize {
+    type Error = String;
+
+    fn try_from(value: ByteSizeValue) -> Result<Self, String> {
+        match value {
+            ByteSizeValue::Bytes(bytes) => Ok(ByteSize(bytes)),
+            ByteSizeValue::Text(text) => text.parse(),
+        }
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        let split = s
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let number: f64 = number.parse().map_err(|_| format!("invalid size {}", s))?;
+        let scale: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+            "" | "b" => 1,
+            "k" | "kib" => 1 << 10,
+            "m" | "mib" => 1 << 20,
+            "g" | "gib" => 1 << 30,
+            "kb" => 1000,
+            "mb" => 1000 * 1000,
+            "gb" => 1000 * 1000 * 1000,
+            _ => return Err(format!("invalid size unit {}", unit.trim())),
+        };
+        Ok(ByteSize((number * scale as f64) as u64))
+    }
+}
+
+impl ByteSize {
+    #[inline]
+    pub fn bytes(self) -> usize {
+        self.0 as usize
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct RateLimitConfig {
     pub max_requests: usize,
 }
 
+/// `locations`, compiled once when the config is loaded.
+#[derive(Clone, Default)]
+pub struct Locations {
+    rules: Vec<Arc<Location>>,
+}
+
+/// One entry of `locations`.
+pub struct Location {
+    /// The key as written in the config.
+    pub key: String,
+    pub modifier: LocationModifier,
+    /// The path of exact and prefix keys without slashes, empty for regexes.
+    pub prefix: String,
+    pub regex: Option<Regex>,
+    pub config: LocationConfig,
+}
+
+/// How a key matches, after nginx: `= /path`, `/path`, `^~ /path`,
+/// `~ regex` and `~* regex`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LocationModifier {
+    Exact,
+    Prefix,
+    /// A prefix which, as the longest one, skips the regexes.
+    PreferPrefix,
+    Regex,
+}
+
+impl Location {
+    fn new(key: &str, config: LocationConfig) -> anyhow::Result<Self> {
+        let (modifier, pattern) = match key.split_once(' ') {
+            Some(("=", path)) => (LocationModifier::Exact, path.trim()),
+            Some(("^~", path)) => (LocationModifier::PreferPrefix, path.trim()),
+            Some(("~", regex)) => (LocationModifier::Regex, regex.trim()),
+            Some(("~*", regex)) => (LocationModifier::Regex, regex.trim()),
+            _ => (LocationModifier::Prefix, key),
+        };
+
+        let (prefix, regex) = if modifier == LocationModifier::Regex {
+            // with no prefix to replace, an alias would map every match to
+            // the same path below it
+            if config.alias.is_some() {
+                anyhow::bail!("alias needs a prefix location, not a regex");
+            }
+            let case = if key.starts_with("~*") { "(?i)" } else { "" };
+            (
+                String::new(),
+                Some(Regex::new(&format!("{}{}", case, pattern))?),
+            )
+        } else {
+            (
+                root_relative(pattern).trim_end_matches('/').to_owned(),
+                None,
+            )
+        };
+
+        Ok(Location {
+            key: key.to_owned(),
+            modifier,
+            prefix,
+            regex,
+            config,
+        })
+    }
+
+    /// Whether the prefix covers `path`, given without slashes.
+    #[inline]
+    fn covers(&self, path: &str) -> bool {
+        self.prefix.is_empty()
+            || path == self.prefix
+            || path
+                .strip_prefix(&self.prefix)
+                .is_some_and(|rest| rest.starts_with('/'))
+    }
+}
+
+impl Locations {
+    /// The location serving `location`, picked like nginx does: an exact
+    /// key, else the longest prefix if it is `^~`, else the first regex
+    /// matching `/location` in config order, else the longest prefix.
+    pub fn find(&self, location: &str) -> Option<&Arc<Location>> {
+        let path = root_relative(location).trim_end_matches('/');
+
+        let mut longest: Option<&Arc<Location>> = None;
+        for rule in &self.rules {
+            match rule.modifier {
+                LocationModifier::Exact if rule.prefix == path => return Some(rule),
+                LocationModifier::Prefix | LocationModifier::PreferPrefix
+                    if rule.covers(path)
+                        && longest.is_none_or(|l| l.prefix.len() < rule.prefix.len()) =>
+                {
+                    longest = Some(rule)
+                }
+                _ => {}
+            }
+        }
+        if longest.is_some_and(|l| l.modifier == LocationModifier::PreferPrefix) {
+            return longest;
+        }
+
+        let uri = format!("/{}", root_relative(location));
+        self.rules
+            .iter()
+            .find(|rule| {
+                rule.regex
+                    .as_ref()
+                    .is_some_and(|regex| regex.is_match(&uri))
+            })
+            .or(longest)
+    }
+
+    /// The settings for `location`: those of every prefix covering it from
+    /// the shortest down, under those of the location `find` picks.
+    pub fn inherited(&self, location: &str) -> Option<LocationConfig> {
+        let found = self.find(location)?;
+        let path = root_relative(location).trim_end_matches('/');
+
+        let mut chain: Vec<&Arc<Location>> = self
+            .rules
+            .iter()
+            .filter(|rule| rule.regex.is_none() && rule.modifier != LocationModifier::Exact)
+            .filter(|rule| rule.covers(path))
+            .collect();
+        chain.sort_by_key(|rule| rule.prefix.len());
+        if !chain.iter().any(|rule| Arc::ptr_eq(rule, found)) {
+            chain.push(found);
+        }
+
+        Some(
+            chain
+                .into_iter()
+                .fold(LocationConfig::default(), |config, rule| {
+                    rule.config.clone().inherit(&config)
+                }),
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for Locations {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct LocationsVisitor;
+
+        impl<'de> de::Visitor<'de> for LocationsVisitor {
+            type Value = Locations;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map of locations")
+            }
+
+            // a map access keeps the keys in config order, which regexes
+            // are tried in
+            fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Locations, A::Error> {
+                let mut rules = vec![];
+                while let Some((key, config)) = map.next_entry::<String, LocationConfig>()? {
+                    let location = Location::new(&key, config)
+                        .map_err(|e| de::Error::custom(format!("location {}: {}", key, e)))?;
+                    rules.push(Arc::new(location));
+                }
+                Ok(Locations { rules })
+            }
+        }
+
+        deserializer.deserialize_map(LocationsVisitor)
+    }
+}
+
+impl Serialize for Locations {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.rules.len()))?;
+        for rule in &self.rules {
+            map.serialize_entry(&rule.key, &rule.config)?;
+        }
+        map.end()
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Default)]
 pub struct LocationConfig {
     pub auto_index: Option<bool>,
-    pub index: Option<PathBuf>,
+    pub index: Option<IndexFiles>,
+    pub error_pages: Option<HashMap<String, ErrorPage>>,
+    pub try_files: Option<Vec<String>>,
+    pub clean_urls: Option<bool>,
+    pub clean_urls_redirect: Option<bool>,
+    pub alias: Option<PathBuf>,
+    pub root: Option<PathBuf>,
+    pub index_template: Option<PathBuf>,
+    pub index_header: Option<Vec<String>>,
+    pub index_readme: Option<Vec<String>>,
+    pub index_footer: Option<Vec<String>>,
+    pub index_markdown: Option<bool>,
+    pub index_limit: Option<usize>,
+    pub index_hide: Option<HidePatterns>,
+    pub index_hide_deny: Option<bool>,
+    pub archive: Option<bool>,
+    pub archive_max_size: Option<ByteSize>,
+    pub archive_max_entries: Option<usize>,
+    pub archive_root: Option<PathBuf>,
+    pub embed: Option<PathBuf>,
+    pub upload: Option<UploadConfig>,
+    pub webdav: Option<WebdavConfig>,
+}
+
+impl LocationConfig {
+    /// These settings over those of the enclosing `parent`. `try_files`,
+    /// `alias`, `archive_root` and `embed` route the location's own prefix, so
+    /// they are not passed down.
+    pub fn inherit(self, parent: &LocationConfig) -> LocationConfig {
+        LocationConfig {
+            auto_index: self.auto_index.or(parent.auto_index),
+            index: self.index.or_else(|| parent.index.clone()),
+            error_pages: self.error_pages.or_else(|| parent.error_pages.clone()),
+            try_files: self.try_files,
+            clean_urls: self.clean_urls.or(parent.clean_urls),
+            clean_urls_redirect: self.clean_urls_redirect.or(parent.clean_urls_redirect),
+            alias: self.alias,
+            root: self.root.or_else(|| parent.root.clone()),
+            index_template: self
+                .index_template
+                .or_else(|| parent.index_template.clone()),
+            index_header: self.index_header.or_else(|| parent.index_header.clone()),
+            index_readme: self.index_readme.or_else(|| parent.index_readme.clone()),
+            index_footer: self.index_footer.or_else(|| parent.index_footer.clone()),
+            index_markdown: self.index_markdown.or(parent.index_markdown),
+            index_limit: self.index_limit.or(parent.index_limit),
+            index_hide: self.index_hide.or_else(|| parent.index_hide.clone()),
+            index_hide_deny: self.index_hide_deny.or(parent.index_hide_deny),
+            archive: self.archive.or(parent.archive),
+            archive_max_size: self.archive_max_size.or(parent.archive_max_size),
+            archive_max_entries: self.archive_max_entries.or(parent.archive_max_entries),
+            archive_root: self.archive_root,
+            embed: self.embed,
+            upload: match (self.upload, &parent.upload) {
+                (Some(upload), Some(parent)) => Some(upload.inherit(parent)),
+                (upload, parent) => upload.or_else(|| parent.clone()),
+            },
+            webdav: match (self.webdav, &parent.webdav) {
+                (Some(webdav), Some(parent)) => Some(webdav.inherit(parent)),
+                (webdav, parent) => webdav.or_else(|| parent.clone()),
+            },
+        }
+    }
+}
+
+/// What a `.zest.yaml` may set for its directory and the ones below: the
+/// settings which cannot reach outside the served tree.
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct DirConfig {
+    pub auto_index: Option<bool>,
+    pub index: Option<IndexFiles>,
+    pub index_header: Option<Vec<String>>,
+    pub index_readme: Option<Vec<String>>,
+    pub index_footer: Option<Vec<String>>,
+    pub index_markdown: Option<bool>,
+    pub index_limit: Option<usize>,
+    pub index_hide: Option<HidePatterns>,
+    pub index_hide_deny: Option<bool>,
+    pub archive: Option<bool>,
+}
+
+impl DirConfig {
+    /// These settings over `config`. They can only narrow what the operator
+    /// allowed: listings and archives may be turned off but not on, hidden
+    /// names are added to and `index_hide_deny` may only be turned on.
+    pub fn apply(&self, config: LocationConfig) -> LocationConfig {
+        LocationConfig {
+            auto_index: self.auto_index.filter(|on| !on).or(config.auto_index),
+            index: self.index.clone().or(config.index),
+            index_header: self.index_header.clone().or(config.index_header),
+            index_readme: self.index_readme.clone().or(config.index_readme),
+            index_footer: self.index_footer.clone().or(config.index_footer),
+            index_markdown: self.index_markdown.or(config.index_markdown),
+            index_limit: self.index_limit.or(config.index_limit),
+            index_hide: match (config.index_hide, &self.index_hide) {
+                (Some(hide), Some(more)) => Some(hide.and(more)),
+                (hide, more) => hide.or_else(|| more.clone()),
+            },
+            index_hide_deny: self
+                .index_hide_deny
+                .filter(|deny| *deny)
+                .or(config.index_hide_deny),
+            archive: self.archive.filter(|on| !on).or(config.archive),
+            ..config
+        }
+    }
+}
+
+/// The `.zest.yaml` of the directory at `location`. Directories are looked
+/// at again once `DIR_CONFIG_RECHECK` has passed and the file is reread when
+/// its mtime changed. Files that fail to parse are logged once and ignored
+/// until they change.
+pub fn dir_config(location: &str) -> Option<Arc<DirConfig>> {
+    let cached = DIR_CONFIGS.lock().unwrap().get(location).cloned();
+    if let Some((checked, _, dir_config)) = &cached {
+        if checked.elapsed() < DIR_CONFIG_RECHECK {
+            return dir_config.clone();
+        }
+    }
+
+    let path = resolve_root(location).ok().map(|dir| dir.join(DIR_CONFIG));
+    let mtime = path
+        .as_ref()
+        .and_then(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok());
+    let changed = cached.as_ref().and_then(|(_, mtime, _)| *mtime) != mtime;
+    let dir_config = match (&cached, path) {
+        (Some((_, _, dir_config)), _) if !changed => dir_config.clone(),
+        (_, Some(path)) if mtime.is_some() => match fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| Ok(serde_yml::from_str::<DirConfig>(&content)?))
+        {
+            Ok(dir_config) => Some(Arc::new(dir_config)),
+            Err(_e) => {
+                #[cfg(feature = "log")]
+                error!("failed to load {}: {}", path.display(), _e);
+                None
+            }
+        },
+        _ => None,
+    };
+
+    let mut dir_configs = DIR_CONFIGS.lock().unwrap();
+    if dir_configs.len() >= MAX_DIR_CONFIGS {
+        dir_configs.clear();
+    }
+    dir_configs.insert(
+        location.to_owned(),
+        (Instant::now(), mtime, dir_config.clone()),
+    );
+    if changed {
+        DIR_CONFIGS_CHANGED.store(true, Ordering::Relaxed);
+    }
+    dir_config
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct UploadConfig {
+    pub max_size: Option<ByteSize>,
+    pub extensions: Option<Vec<String>>,
+    pub overwrite: Option<OverwritePolicy>,
+    pub auth: Option<Vec<String>>,
+}
+
+impl UploadConfig {
+    /// These settings over those of an enclosing location.
+    pub fn inherit(self, parent: &UploadConfig) -> UploadConfig {
+        UploadConfig {
+            max_size: self.max_size.or(parent.max_size),
+            extensions: self.extensions.or_else(|| parent.extensions.clone()),
+            overwrite: self.overwrite.or(parent.overwrite),
+            auth: self.auth.or_else(|| parent.auth.clone()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct WebdavConfig {
+    pub read_only: Option<bool>,
+    pub max_size: Option<ByteSize>,
+    pub auth: Option<Vec<String>>,
+}
+
+impl WebdavConfig {
+    /// These settings over those of an enclosing location.
+    pub fn inherit(self, parent: &WebdavConfig) -> WebdavConfig {
+        WebdavConfig {
+            read_only: self.read_only.or(parent.read_only),
+            max_size: self.max_size.or(parent.max_size),
+            auth: self.auth.or_else(|| parent.auth.clone()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum OverwritePolicy {
+    #[default]
+    Deny,
+    Replace,
+    Rename,
 }
 
 #[derive(Serialize, Deserialize, Clone, Default)]
@@ -113,12 +694,32 @@ pub struct Args {
     pub config: Option<String>,
 }
 
-pub fn init_config() -> Config {
-    let config_path = CONFIG_PATH.lock().unwrap();
-    let default_config = Config::default();
+/// Reads the config file, or takes the defaults when there is none.
+pub fn load_config() -> anyhow::Result<Config> {
+    let config_path = CONFIG_PATH.lock().unwrap().clone();
 
-    match fs::read_to_string(config_path.to_owned()) {
-        Ok(conf) => serde_yml::from_str(&conf).unwrap_or(default_config),
-        _ => default_config,
-    }
+    let mut config: Config = match fs::read_to_string(&config_path) {
+        Ok(conf) => {
+            serde_yml::from_str(&conf).with_context(|| format!("failed to load {}", config_path))?
+        }
+        _ => Config::default(),
+    };
+
+    // the working directory moves to the root, so relative roots would
+    // otherwise be taken from there once the config is reloaded
+    config.server.root = match config.server.root {
+        Root::Dir(dir) => Root::Dir(START_DIR.join(dir)),
+        Root::Layers(layers) => {
+            Root::Layers(layers.iter().map(|dir| START_DIR.join(dir)).collect())
+        }
+    };
+    Ok(config)
+}
+
+/// The config zest starts with, exiting when it fails to load.
+pub fn init_config() -> Config {
+    load_config().unwrap_or_else(|e| {
+        eprintln!("{e:#}");
+        process::exit(1);
+    })
 }
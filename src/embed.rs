@@ -0,0 +1,134 @@
+use crate::{
+    route::mime_match,
+    storage::{dir_prefix, Metadata, Storage, StorageEntry, StorageReader},
+};
+use flate2::read::GzDecoder;
+use futures::{future::BoxFuture, FutureExt};
+use lazy_static::lazy_static;
+use mime::Mime;
+use std::{
+    collections::BTreeMap,
+    io::{self, Cursor, Read},
+    ops::Range,
+};
+
+/// A file compiled into the binary from `ZEST_EMBED_DIR`, kept gzipped.
+pub struct EmbeddedFile {
+    pub path: &'static str,
+    pub data: &'static [u8],
+    pub size: u64,
+    pub mtime: i64,
+    pub etag: &'static str,
+}
+
+impl EmbeddedFile {
+    /// Decompresses the file.
+    pub fn content(&self) -> io::Result<Vec<u8>> {
+        let mut content = Vec::with_capacity(self.size as usize);
+        GzDecoder::new(self.data).read_to_end(&mut content)?;
+        Ok(content)
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/embedded.rs"));
+
+lazy_static! {
+    /// Embedded files with their MIME type by path, directories mapping to
+    /// `None`. The root is the empty path.
+    static ref EMBEDDED_TREE: BTreeMap<&'static str, Option<(&'static EmbeddedFile, Mime)>> = {
+        let mut tree = BTreeMap::from([("", None)]);
+        for file in EMBEDDED_FILES {
+            let mut parent = file.path;
+            while let Some((dir, _)) = parent.rsplit_once('/') {
+                parent = dir;
+                tree.insert(dir, None);
+            }
+            tree.insert(file.path, Some((file, mime_match(file.path))));
+        }
+        tree
+    };
+}
+
+/// The files embedded into the binary, gzip-encoded when served to clients
+/// accepting it.
+pub struct EmbeddedStorage;
+
+impl EmbeddedStorage {
+    fn node(path: &str) -> io::Result<Option<&'static (&'static EmbeddedFile, Mime)>> {
+        EMBEDDED_TREE
+            .get(path)
+            .map(|node| node.as_ref())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn file(path: &str) -> io::Result<&'static EmbeddedFile> {
+        Self::node(path)?
+            .map(|(file, _)| *file)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::IsADirectory))
+    }
+
+    #[inline]
+    fn metadata(node: Option<&(&'static EmbeddedFile, Mime)>) -> Metadata {
+        match node {
+            Some((file, mime)) => Metadata {
+                size: file.size,
+                mtime: file.mtime,
+                etag: Some(file.etag.to_owned()),
+                mime: Some(mime.clone()),
+                ..Default::default()
+            },
+            None => Metadata {
+                dir: true,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl Storage for EmbeddedStorage {
+    fn open<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<StorageReader>> {
+        async move { Ok(Box::new(Cursor::new(Self::file(path)?.content()?)) as StorageReader) }
+            .boxed()
+    }
+
+    fn stat<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<Metadata>> {
+        async move { Ok(Self::metadata(Self::node(path)?)) }.boxed()
+    }
+
+    fn read_dir<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<Vec<StorageEntry>>> {
+        async move {
+            if Self::node(path)?.is_some() {
+                return Err(io::Error::from(io::ErrorKind::NotADirectory));
+            }
+
+            let prefix = dir_prefix(path);
+            Ok(EMBEDDED_TREE
+                .range(prefix.as_str()..)
+                .take_while(|(name, _)| name.starts_with(&prefix))
+                .filter(|(name, _)| !name.is_empty() && !name[prefix.len()..].contains('/'))
+                .map(|(name, node)| StorageEntry {
+                    name: name[prefix.len()..].to_owned(),
+                    meta: Self::metadata(node.as_ref()),
+                })
+                .collect())
+        }
+        .boxed()
+    }
+
+    fn read_range<'a>(
+        &'a self,
+        path: &'a str,
+        range: Range<u64>,
+    ) -> BoxFuture<'a, io::Result<Vec<u8>>> {
+        async move {
+            let content = Self::file(path)?.content()?;
+            let end = (range.end as usize).min(content.len());
+            Ok(content[(range.start as usize).min(end)..end].to_vec())
+        }
+        .boxed()
+    }
+
+    fn read_gzip<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<Option<Vec<u8>>>> {
+        async move { Ok(Some(Self::file(path)?.data.to_vec())) }.boxed()
+    }
+}
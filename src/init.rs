@@ -1,12 +1,24 @@
-use crate::config::{CONFIG, DEFAULT_CACHE_INTERVAL, DEFAULT_CONFIG};
 use async_mutex::Mutex;
-use async_rwlock::RwLock;
 use lazy_static::lazy_static;
 use log4rs::Handle;
-use std::{io, num::NonZeroUsize, path::PathBuf, thread};
+use std::path::PathBuf;
+
+#[cfg(any(feature = "lru_cache", feature = "index_template"))]
+use async_rwlock::RwLock;
 
 #[cfg(feature = "lru_cache")]
-use lru::LruCache;
+use {
+    crate::{
+        cache::WeightedCache,
+        config::{CacheConfig, CONFIG, DEFAULT_CACHE_INTERVAL, DEFAULT_CONFIG},
+    },
+    lru::LruCache,
+    std::{io, num::NonZeroUsize},
+    tokio::time::sleep,
+};
+
+#[cfg(feature = "index_template")]
+use minijinja::{AutoEscape, Environment};
 
 #[cfg(feature = "log")]
 use {
@@ -30,36 +42,46 @@ lazy_static! {
 
 #[cfg(feature = "lru_cache")]
 lazy_static! {
-    pub static ref INDEX_CACHE: RwLock<LruCache<String, String>> = {
-        let cache = LruCache::new(
-            NonZeroUsize::new(
-                DEFAULT_CONFIG
-                    .server
-                    .cache
-                    .clone()
-                    .unwrap_or_default()
-                    .index_capacity
-                    .unwrap_or(16),
-            )
-            .unwrap(),
-        );
-        RwLock::new(cache)
+    pub static ref INDEX_CACHE: RwLock<WeightedCache<String>> = {
+        let cache = DEFAULT_CONFIG.server.cache.clone().unwrap_or_default();
+        RwLock::new(WeightedCache::new(index_budget(&cache)))
     };
-    pub static ref FILE_CACHE: RwLock<LruCache<String, Vec<u8>>> = {
-        let cache = LruCache::new(
-            NonZeroUsize::new(
-                DEFAULT_CONFIG
-                    .server
-                    .cache
-                    .clone()
-                    .unwrap_or_default()
-                    .file_capacity
-                    .unwrap_or(32),
-            )
-            .unwrap(),
-        );
-        RwLock::new(cache)
+    pub static ref FILE_CACHE: RwLock<WeightedCache<Vec<u8>>> = {
+        let cache = DEFAULT_CONFIG.server.cache.clone().unwrap_or_default();
+        RwLock::new(WeightedCache::new(file_budget(&cache)))
     };
+    /// Extensionless locations that `clean_urls` served from `.html` files,
+    /// or `false` for those neither existing nor having one.
+    pub static ref CLEAN_URL_CACHE: RwLock<LruCache<String, bool>> =
+        RwLock::new(LruCache::new(NonZeroUsize::new(CLEAN_URL_CAPACITY).unwrap()));
+}
+
+#[cfg(feature = "lru_cache")]
+const CLEAN_URL_CAPACITY: usize = 1024;
+
+/// Bytes `INDEX_CACHE` may hold.
+#[cfg(feature = "lru_cache")]
+pub fn index_budget(cache: &CacheConfig) -> usize {
+    cache
+        .index_budget
+        .or(CacheConfig::default().index_budget)
+        .unwrap()
+        .bytes()
+}
+
+/// Bytes `FILE_CACHE` may hold.
+#[cfg(feature = "lru_cache")]
+pub fn file_budget(cache: &CacheConfig) -> usize {
+    cache
+        .file_budget
+        .or(CacheConfig::default().file_budget)
+        .unwrap()
+        .bytes()
+}
+
+#[cfg(feature = "index_template")]
+lazy_static! {
+    pub static ref TEMPLATES: RwLock<Environment<'static>> = RwLock::new(init_templates());
 }
 
 pub const DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
@@ -177,11 +199,22 @@ pub async fn init_cache() -> io::Result<()> {
                 }
             } else if let Some(mut file_cache) = FILE_CACHE.try_write() {
                 file_cache.clear();
+                if let Some(mut clean_url_cache) = CLEAN_URL_CACHE.try_write() {
+                    clean_url_cache.clear();
+                }
             }
             _b = !_b;
-            thread::sleep(interval);
+            sleep(interval).await;
         }
     });
 
     Ok(())
 }
+
+/// Builds an empty template environment, templates are loaded on first use.
+#[cfg(feature = "index_template")]
+pub fn init_templates() -> Environment<'static> {
+    let mut env = Environment::new();
+    env.set_auto_escape_callback(|_| AutoEscape::Html);
+    env
+}
@@ -1,14 +1,91 @@
 #[macro_use]
 pub mod macros;
 
+#[cfg(feature = "archive")]
+pub mod archive;
+#[cfg(feature = "lru_cache")]
+pub mod cache;
 pub mod config;
+#[cfg(feature = "embed")]
+pub mod embed;
 pub mod init;
 pub mod route;
+pub mod rules;
 pub mod server;
+pub mod storage;
+#[cfg(feature = "upload")]
+pub mod upload;
+#[cfg(feature = "webdav")]
+pub mod webdav;
 
 #[cfg(test)]
 mod tests {
-    use super::route::mime_match;
+    use super::{
+        config::{
+            ByteSize, CacheConfig, DirConfig, LocationConfig, Locations, Root, SymlinkPolicy,
+            CONFIG, DIR_CONFIG_RECHECK,
+        },
+        route::{
+            first_entries, human_size, location_config, location_index, mime_match,
+            normalize_location, query_params, resolve_path, try_files, HidePatterns, Index,
+            IndexDecorations, IndexEntry, IndexFormat, IndexOptions, NameFilter,
+        },
+        rules::{SiteAction, SiteRules},
+        storage::{
+            location_alias, location_storage, parse_passwd, read, resolve_root, MemoryStorage,
+            Metadata, OverlayStorage, Storage,
+        },
+    };
+    use futures::stream;
+    use std::{
+        env::temp_dir,
+        fs,
+        os::unix::fs::symlink,
+        path::{Path, PathBuf},
+        sync::{Arc, Once},
+    };
+
+    /// Locations of the tests serving files from `test_root`, `$ROOT`.
+    const TEST_LOCATIONS: &str = r#"
+        /arch: { auto_index: true, archive: true, index_hide: ["*.tmp"] }
+        /arch/few: { archive_max_entries: 3 }
+        /arch/small: { archive_max_size: 10 }
+        /up: { upload: { extensions: [txt], overwrite: rename, auth: ["u:p"] } }
+        /dav1: { webdav: { auth: ["a:1"] } }
+        /dav2: { webdav: { auth: ["b:2"] } }
+        /dav3: { webdav: { auth: ["a:1"], max_size: 3B } }
+        /al: { alias: $ROOT/aliased }
+        /mnt: { archive_root: mount/t.zip }
+        /clean:
+          clean_urls: true
+          clean_urls_redirect: true
+          upload: { extensions: [html], overwrite: replace }
+        /rt: { root: $ROOT/rooted }
+    "#;
+
+    /// A root directory shared by the tests that serve files, each below a
+    /// directory of its own, made the server root with `TEST_LOCATIONS`.
+    fn test_root() -> PathBuf {
+        static INIT: Once = Once::new();
+        let root = temp_dir().join(format!("zest-test-{}", std::process::id()));
+        INIT.call_once(|| {
+            fs::create_dir_all(&root).unwrap();
+            let locations = TEST_LOCATIONS.replace("$ROOT", root.to_str().unwrap());
+            let locations: Locations = serde_yml::from_str(&locations).unwrap();
+            CONFIG.rcu(|config| {
+                let mut config = (**config).clone();
+                config.server.root = Root::Dir(root.clone());
+                config.locations = Some(locations.clone());
+                config
+            });
+        });
+        root
+    }
+
+    fn write(path: &Path, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
 
     #[test]
     fn mime_test() {
@@ -17,4 +94,1156 @@ mod tests {
         assert_eq!(mime_match("data.bin"), mime::APPLICATION_OCTET_STREAM);
         assert_eq!(mime_match("index.html"), mime::TEXT_HTML);
     }
+
+    #[test]
+    fn resolve_path_test() {
+        let root = temp_dir().join("zest_resolve_path_test");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("public/.git")).unwrap();
+        fs::write(root.join("secret.txt"), "").unwrap();
+        fs::write(root.join("public/index.html"), "").unwrap();
+        let _ = symlink(root.join("secret.txt"), root.join("public/link.txt"));
+
+        let public = root.join("public");
+        let follow = SymlinkPolicy::Follow;
+        assert!(resolve_path(&public, "index.html", follow, false).is_ok());
+        assert!(resolve_path(&public, "a/../index.html", follow, false).is_ok());
+        assert_eq!(
+            resolve_path(&public, "../secret.txt", follow, false),
+            Err(403)
+        );
+        assert_eq!(
+            resolve_path(&public, "/etc/passwd", follow, false),
+            Err(404)
+        );
+        assert_eq!(resolve_path(&public, "link.txt", follow, false), Err(403));
+        assert_eq!(resolve_path(&public, "missing", follow, false), Err(404));
+        assert_eq!(resolve_path(&public, ".git", follow, true), Err(404));
+        assert!(resolve_path(&public, ".git", follow, false).is_ok());
+        assert_eq!(
+            resolve_path(&root, "public/link.txt", SymlinkPolicy::Deny, false),
+            Err(403)
+        );
+        assert!(resolve_path(&root, "public/link.txt", SymlinkPolicy::OwnerMatch, false).is_ok());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn normalize_location_test() {
+        assert_eq!(normalize_location("/a/./b/../c/"), Ok("a/c".to_owned()));
+        assert_eq!(normalize_location("a/.."), Ok("".to_owned()));
+        assert_eq!(normalize_location("a/../../b"), Err(403));
+    }
+
+    #[test]
+    fn try_files_test() {
+        let resolve = |location: &str| match location {
+            "/app/main.js" | "/app/about.html" | "/app/index.html" => Ok(PathBuf::from(location)),
+            _ => Err(404),
+        };
+        let spa: Vec<String> = ["$uri", "$uri.html", "/app/index.html"]
+            .map(String::from)
+            .to_vec();
+        let strict: Vec<String> = ["$uri", "=410"].map(String::from).to_vec();
+
+        let resolved = |location| try_files(&spa, location, resolve).map(|(l, _)| l);
+        assert_eq!(resolved("app/main.js"), Ok("app/main.js".to_owned()));
+        assert_eq!(resolved("app/about"), Ok("app/about.html".to_owned()));
+        assert_eq!(resolved("app/deep/route"), Ok("app/index.html".to_owned()));
+        assert_eq!(
+            try_files(&strict, "app/missing", resolve).map(|(l, _)| l),
+            Err(410)
+        );
+    }
+
+    #[test]
+    fn index_format_test() {
+        let params = query_params("format=json&q=a+b%2Fc");
+        assert_eq!(params.get("q").map(|s| s.as_str()), Some("a b/c"));
+
+        let negotiate = |format: Option<&str>, accept| IndexFormat::negotiate(format, Some(accept));
+        assert_eq!(negotiate(Some("json"), "text/html"), IndexFormat::Json);
+        assert_eq!(negotiate(None, "application/json"), IndexFormat::Json);
+        assert_eq!(negotiate(None, "text/plain; q=0.9, */*"), IndexFormat::Html);
+        assert_eq!(negotiate(None, "text/plain, */*;q=0.1"), IndexFormat::Text);
+        assert_eq!(
+            negotiate(None, "application/json;q=0, text/html"),
+            IndexFormat::Html
+        );
+        assert_eq!(
+            negotiate(None, "text/*;q=0.3, application/json;q=0.5"),
+            IndexFormat::Json
+        );
+        assert_eq!(negotiate(None, "text/html;q=0, text/*"), IndexFormat::Text);
+        assert_eq!(
+            negotiate(None, "text/html,application/json"),
+            IndexFormat::Html
+        );
+        assert_eq!(negotiate(None, "*/*"), IndexFormat::Html);
+    }
+
+    #[test]
+    fn human_size_test() {
+        assert_eq!(human_size(512), "512 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(32 * 1024 * 1024), "32.0 MiB");
+    }
+
+    #[test]
+    fn gzip_etag_test() {
+        use super::server::gzip_etag;
+
+        assert_eq!(gzip_etag("\"5f-1a\""), "\"5f-1a-gz\"");
+        assert_eq!(gzip_etag("W/\"x\""), "W/\"x-gz\"");
+    }
+
+    #[test]
+    fn hide_patterns_test() {
+        let hide = HidePatterns::new(vec!["*.tmp".to_owned(), ".DS_Store".to_owned()]).unwrap();
+        assert!(hide.is_match("upload.tmp"));
+        assert!(!hide.is_match("upload.tmp.txt"));
+        assert!(hide.is_hidden("a/.DS_Store"));
+        assert!(hide.is_hidden("cache.tmp/file.txt"));
+        assert!(!hide.is_hidden("docs/index.html"));
+        assert!(HidePatterns::default().is_match(".zest.yaml"));
+        assert!(HidePatterns::default().is_match(".a.txt.12-3.upload"));
+        assert!(!HidePatterns::default().is_match(".a.upload"));
+        assert!(serde_yml::from_str::<LocationConfig>("index_hide: ['*.{a,b']").is_err());
+
+        assert!(NameFilter::new("READ").is_match("readme.md"));
+        assert!(NameFilter::new("*.MD").is_match("readme.md"));
+        assert!(!NameFilter::new("*.md").is_match("readme.txt"));
+    }
+
+    #[test]
+    fn locations_test() {
+        let locations: Locations = serde_yml::from_str(
+            r#"
+            /: { index: root.html }
+            = /: { index: exact.html }
+            /static: { index: static.html }
+            ^~ /static/fonts: { index: fonts.html }
+            ~* \.(png|jpg)$: { index: image.html }
+            ~ ^/api/v[0-9]+/: { index: api.html }
+            /api: { index: [api-prefix.html, index.htm] }
+            "#,
+        )
+        .unwrap();
+        let index = |location| {
+            let index = locations.find(location)?.config.index.clone()?;
+            Some(index.files()[0].display().to_string())
+        };
+
+        assert_eq!(index("/").as_deref(), Some("exact.html"));
+        assert_eq!(index("/docs/a.html").as_deref(), Some("root.html"));
+        assert_eq!(index("/static/a.css").as_deref(), Some("static.html"));
+        assert_eq!(index("/static/a.PNG").as_deref(), Some("image.html"));
+        assert_eq!(index("/static/fonts/a.png").as_deref(), Some("fonts.html"));
+        assert_eq!(index("/staticx").as_deref(), Some("root.html"));
+        assert_eq!(index("/api/v2/users").as_deref(), Some("api.html"));
+        assert_eq!(index("/api/users").as_deref(), Some("api-prefix.html"));
+        assert!(serde_yml::from_str::<Locations>("~ (: {}").is_err());
+        assert!(serde_yml::from_str::<Locations>("~ ^/a: { alias: /srv/a }").is_err());
+
+        let locations: Locations = serde_yml::from_str(
+            r#"
+            /: { auto_index: true, index_limit: 10 }
+            /a: { auto_index: false, alias: /srv/a }
+            /a/b: { index_limit: 20 }
+            "#,
+        )
+        .unwrap();
+        let inherited = locations.inherited("/a/b/c").unwrap();
+        assert_eq!(inherited.auto_index, Some(false));
+        assert_eq!(inherited.index_limit, Some(20));
+        assert!(inherited.alias.is_none());
+
+        let locations: Locations = serde_yml::from_str(
+            r#"
+            /u: { upload: { auth: ["u:p"], max_size: 10K }, webdav: { read_only: true } }
+            /u/v: { upload: { extensions: [txt] }, webdav: { max_size: 5 } }
+            "#,
+        )
+        .unwrap();
+        let inherited = locations.inherited("/u/v/w").unwrap();
+        let (upload, webdav) = (inherited.upload.unwrap(), inherited.webdav.unwrap());
+        assert_eq!(upload.auth, Some(vec!["u:p".to_owned()]));
+        assert_eq!(upload.max_size, Some(ByteSize(10 * 1024)));
+        assert_eq!(upload.extensions, Some(vec!["txt".to_owned()]));
+        assert_eq!(webdav.read_only, Some(true));
+        assert_eq!(webdav.max_size, Some(ByteSize(5)));
+    }
+
+    #[tokio::test]
+    async fn location_alias_test() {
+        let root = test_root();
+        write(&root.join("aliased/x/a.txt"), "alias");
+        write(&root.join("rooted/rt/b.txt"), "root");
+        write(&root.join("secret.txt"), "secret");
+        let _ = symlink(root.join("secret.txt"), root.join("aliased/out.txt"));
+
+        // alias strips the matched prefix, root keeps it
+        assert_eq!(
+            location_alias("/al/x/a.txt"),
+            Ok(Some((root.join("aliased"), "x/a.txt".to_owned())))
+        );
+        assert_eq!(
+            location_alias("/rt/b.txt"),
+            Ok(Some((root.join("rooted"), "rt/b.txt".to_owned())))
+        );
+        let (storage, path) = location_storage("/al/x/a.txt").await.unwrap();
+        assert_eq!(read(&*storage, &path).await.unwrap(), b"alias");
+        let (storage, path) = location_storage("/rt/b.txt").await.unwrap();
+        assert_eq!(read(&*storage, &path).await.unwrap(), b"root");
+
+        // neither `..` nor a symlink leaves the mapped directory
+        assert_eq!(location_alias("/al/../secret.txt"), Ok(None));
+        assert_eq!(location_alias("/../al"), Err(403));
+        assert_eq!(resolve_root("/al/out.txt"), Err(403));
+        let (storage, path) = location_storage("/al/out.txt").await.unwrap();
+        assert!(read(&*storage, &path).await.is_err());
+        assert!(resolve_root("/al/x/a.txt").is_ok());
+    }
+
+    #[test]
+    fn dir_config_test() {
+        assert!(serde_yml::from_str::<DirConfig>("alias: /etc").is_err());
+
+        // a `.zest.yaml` can only narrow what its location allows
+        let location: LocationConfig = serde_yml::from_str(
+            "{ auto_index: false, index_hide: ['*.a'], index_hide_deny: true }",
+        )
+        .unwrap();
+        let dir_config: DirConfig = serde_yml::from_str(
+            "{ auto_index: true, index_hide: ['*.b'], index_hide_deny: false, archive: true }",
+        )
+        .unwrap();
+        let applied = dir_config.apply(location);
+        assert_eq!(applied.auto_index, Some(false));
+        assert_eq!(applied.index_hide_deny, Some(true));
+        assert_eq!(applied.archive, None);
+        let hide = applied.index_hide.unwrap();
+        assert!(hide.is_match("x.a") && hide.is_match("x.b") && !hide.is_match("x.c"));
+
+        let dir_config: DirConfig =
+            serde_yml::from_str("{ auto_index: false, index_hide_deny: true, archive: false }")
+                .unwrap();
+        let applied = dir_config.apply(LocationConfig {
+            auto_index: Some(true),
+            archive: Some(true),
+            ..Default::default()
+        });
+        assert_eq!(applied.auto_index, Some(false));
+        assert_eq!(applied.index_hide_deny, Some(true));
+        assert_eq!(applied.archive, Some(false));
+
+        // changes are seen once the directory is looked at again
+        let path = test_root().join("dirconf/.zest.yaml");
+        let limit = || location_config("/dirconf/sub").and_then(|l| l.index_limit);
+        write(&path, "index_limit: 5");
+        assert_eq!(limit(), Some(5));
+        write(&path, "index_limit: 7");
+        assert_eq!(limit(), Some(5));
+        std::thread::sleep(DIR_CONFIG_RECHECK);
+        assert_eq!(limit(), Some(7));
+        fs::remove_file(&path).unwrap();
+        std::thread::sleep(DIR_CONFIG_RECHECK);
+        assert_eq!(limit(), None);
+    }
+
+    #[tokio::test]
+    async fn site_headers_test() {
+        use super::{rules::SITE_RULES, server::handle_connection};
+        use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+        write(&test_root().join("hdr/a.html"), "a");
+        let (rules, _) = SiteRules::parse("", "/hdr/*\n  content-type: text/plain\n  x-test: 1\n");
+        SITE_RULES.store(Arc::new(rules));
+
+        for path in ["/hdr/a.html", "/x/../hdr/a.html", "/hdr//a.html"] {
+            let (mut client, server) = duplex(64 * 1024);
+            let request = format!("GET {} HTTP/1.1\r\n\r\n", path);
+            client.write_all(request.as_bytes()).await.unwrap();
+            handle_connection(server).await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            let response = response.to_lowercase();
+            assert!(response.contains("\r\nx-test: 1\r\n"), "{}", path);
+            assert_eq!(response.matches("\r\ncontent-type:").count(), 1, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn range_test() {
+        use super::server::{byte_range, handle_connection};
+        use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+        assert_eq!(byte_range("bytes=1-3", 10), Ok(Some(1..4)));
+        assert_eq!(byte_range("bytes=8-", 10), Ok(Some(8..10)));
+        assert_eq!(byte_range("bytes=-4", 10), Ok(Some(6..10)));
+        assert_eq!(byte_range("bytes=5-99", 10), Ok(Some(5..10)));
+        assert_eq!(byte_range("bytes=10-", 10), Err(416));
+        assert_eq!(byte_range("bytes=-0", 10), Err(416));
+        for ignored in ["bytes=3-1", "bytes=0-1,4-5", "items=0-1", "bytes=x-"] {
+            assert_eq!(byte_range(ignored, 10), Ok(None), "{}", ignored);
+        }
+
+        write(&test_root().join("range/r.txt"), "0123456789");
+        let get = |headers: &str| {
+            let request = format!("GET /range/r.txt HTTP/1.1\r\n{}\r\n", headers);
+            async move {
+                let (mut client, server) = duplex(64 * 1024);
+                client.write_all(request.as_bytes()).await.unwrap();
+                handle_connection(server).await.unwrap();
+                let mut response = String::new();
+                client.read_to_string(&mut response).await.unwrap();
+                response
+            }
+        };
+
+        let response = get("Range: bytes=1-3\r\n").await;
+        assert!(
+            response.starts_with("HTTP/1.1 206 Partial Content\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.contains("Content-Range: bytes 1-3/10\r\n"));
+        assert!(response.ends_with("\r\n\r\n123"));
+
+        let response = get("Range: bytes=20-\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 416"), "{}", response);
+        assert!(response.contains("Content-Range: bytes */10\r\n"));
+
+        // a stale If-Range gets the whole file
+        let response = get("Range: bytes=1-3\r\nIf-Range: \"old\"\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("Accept-Ranges: bytes\r\n"));
+        assert!(response.ends_with("0123456789"));
+    }
+
+    #[tokio::test]
+    async fn clean_urls_test() {
+        use super::server::handle_connection;
+        use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+        let root = test_root().join("clean");
+        write(&root.join("about.html"), "about");
+        write(&root.join("index.html"), "home");
+        write(&root.join("docs/index.html"), "docs");
+
+        let request = |request: String| async move {
+            let (mut client, server) = duplex(64 * 1024);
+            client.write_all(request.as_bytes()).await.unwrap();
+            handle_connection(server).await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let get = |path: &str| request(format!("GET {} HTTP/1.1\r\n\r\n", path));
+        let status = |response: &str| response[9..12].to_owned();
+
+        let response = get("/clean/about").await;
+        assert_eq!(status(&response), "200");
+        assert!(response.ends_with("about"));
+        for path in ["/clean/about/", "/clean/about.html"] {
+            let response = get(path).await;
+            assert_eq!(status(&response), "301", "{}", path);
+            assert!(response.contains("Location: /clean/about\r\n"), "{}", path);
+        }
+        // index files keep their name
+        assert_eq!(status(&get("/clean/index.html").await), "200");
+        assert_eq!(status(&get("/clean/docs/index.html").await), "200");
+        assert_eq!(status(&get("/clean/docs/").await), "200");
+
+        // misses are remembered until a write brings the page in
+        assert_eq!(status(&get("/clean/new").await), "404");
+        write(&root.join("new.html"), "new");
+        if cfg!(feature = "lru_cache") {
+            assert_eq!(status(&get("/clean/new").await), "404");
+        }
+        #[cfg(feature = "upload")]
+        {
+            let put = "PUT /clean/new.html HTTP/1.1\r\nContent-Length: 3\r\n\r\nnew";
+            assert_eq!(status(&request(put.to_owned()).await), "204");
+            assert_eq!(status(&get("/clean/new").await), "200");
+        }
+    }
+
+    #[tokio::test]
+    async fn site_rules_test() {
+        let (rules, errors) = SiteRules::parse(
+            r#"
+            # comment
+            /old            /new
+            /news/:year/*   /blog/:year/:splat  302
+            /store id=:id   /products/:id       301
+            /app/*          /app/index.html     200
+            /gone           /410.html           410!
+            /proxy          https://example.com 200
+            /geo            /fr                 302 Country=fr
+            "#,
+            "/*\n  X-Frame-Options: DENY\n/assets/*\n  Cache-Control: max-age=60\n  X-Robots-Tag: noindex\nX-Bad: 1\n",
+        );
+        let lines: Vec<_> = errors.iter().map(|(file, n, _)| (*file, *n)).collect();
+        assert_eq!(
+            lines,
+            [("_redirects", 8), ("_redirects", 9), ("_headers", 6)]
+        );
+
+        let action = async |target| {
+            let exists = async |location: &str| location == "app/main.js";
+            rules.action(target, exists).await
+        };
+        assert_eq!(
+            action("/old?a=1").await,
+            Some(SiteAction::Redirect(301, "/new?a=1".to_owned()))
+        );
+        assert_eq!(
+            action("/news/2024/05/hello").await,
+            Some(SiteAction::Redirect(302, "/blog/2024/05/hello".to_owned()))
+        );
+        assert_eq!(
+            action("/store?id=42").await,
+            Some(SiteAction::Redirect(301, "/products/42".to_owned()))
+        );
+        assert_eq!(action("/store").await, None);
+        assert_eq!(
+            action("/app/deep/route").await,
+            Some(SiteAction::Rewrite(200, "app/index.html".to_owned()))
+        );
+        assert_eq!(action("/app/main.js").await, None);
+        assert_eq!(
+            action("/gone").await,
+            Some(SiteAction::Rewrite(410, "410.html".to_owned()))
+        );
+
+        assert_eq!(rules.headers("/index.html"), [("X-Frame-Options", "DENY")]);
+        assert_eq!(rules.headers("/assets/a.css").len(), 3);
+    }
+
+    #[test]
+    fn parse_passwd_test() {
+        let passwd = "root:x:0:0:root:/root:/bin/bash\n\
+                      alice:x:1000:1000:Alice,,,:/home/alice:/bin/zsh\n\
+                      broken:x:1001\n\
+                      alice:x:1002:1002::/home/other:/bin/sh\n";
+        let homes = parse_passwd(passwd);
+        assert_eq!(homes.get("alice"), Some(&PathBuf::from("/home/alice")));
+        assert_eq!(homes.get("ali"), None);
+        assert_eq!(homes.get("broken"), None);
+    }
+
+    #[tokio::test]
+    async fn user_dir_test() {
+        use super::{config::UserDirConfig, storage::PASSWD_HOMES};
+
+        let home = test_root().join("home/zest");
+        write(&home.join("public_html/a.txt"), "mine");
+        write(&home.join("secret.txt"), "secret");
+        let _ = symlink(home.join("secret.txt"), home.join("public_html/out.txt"));
+        CONFIG.rcu(|config| {
+            let mut config = (**config).clone();
+            config.server.userdir = Some(UserDirConfig::default());
+            config
+        });
+        // stands in for the parsed /etc/passwd until it changes
+        let mtime = fs::metadata("/etc/passwd").and_then(|meta| meta.modified());
+        let homes = [("zest".to_owned(), home.clone())].into();
+        *PASSWD_HOMES.lock().unwrap() = Some((mtime.unwrap(), Arc::new(homes)));
+
+        assert_eq!(
+            location_alias("/~zest/a.txt"),
+            Ok(Some((home.join("public_html"), "a.txt".to_owned())))
+        );
+        let (storage, path) = location_storage("/~zest/a.txt").await.unwrap();
+        assert_eq!(read(&*storage, &path).await.unwrap(), b"mine");
+        assert_eq!(location_alias("/~nobody/a.txt"), Err(404));
+
+        // nothing outside public_html is reachable
+        assert_eq!(location_alias("/~zest/../secret.txt"), Ok(None));
+        assert_eq!(location_alias("/~zest/../../secret.txt"), Err(403));
+        assert_eq!(resolve_root("/~zest/out.txt"), Err(403));
+    }
+
+    #[test]
+    fn byte_size_test() {
+        let size = |s: &str| serde_yml::from_str::<ByteSize>(s).map(|size| size.0).ok();
+        assert_eq!(size("4096"), Some(4096));
+        assert_eq!(size("64MiB"), Some(64 * 1024 * 1024));
+        assert_eq!(size("512 KB"), Some(512_000));
+        assert_eq!(size("1.5k"), Some(1536));
+        assert_eq!(size("12 parsecs"), None);
+
+        let cache = |s: &str| {
+            serde_yml::from_str::<CacheConfig>(s)
+                .err()
+                .map(|e| e.to_string())
+        };
+        assert!(cache("index_budget: 1K").is_none());
+        assert!(cache("index_capacity: 16")
+            .unwrap()
+            .contains("index_budget"));
+        assert!(cache("file_capacity: 32").unwrap().contains("file_budget"));
+    }
+
+    #[cfg(feature = "lru_cache")]
+    #[test]
+    fn weighted_cache_test() {
+        let mut cache = crate::cache::WeightedCache::new(10);
+        assert!(cache.put("a".to_owned(), vec![0; 4]));
+        assert!(cache.put("b".to_owned(), vec![0; 4]));
+        assert!(cache.get("a").is_some());
+        assert!(cache.put("c".to_owned(), vec![0; 4]));
+        assert!(cache.get("b").is_none());
+        assert_eq!(cache.used(), 10);
+        assert!(!cache.put("d".to_owned(), vec![0; 10]));
+
+        cache.resize(5);
+        assert!(cache.get("a").is_none());
+        assert_eq!(cache.used(), 5);
+    }
+
+    #[tokio::test]
+    async fn storage_test() {
+        let upper = MemoryStorage::new();
+        upper.insert("index.html", "upper", 2);
+        let lower = MemoryStorage::new();
+        lower.insert("index.html", "lower", 1);
+        lower.insert("docs/guide.md", "# guide", 1);
+
+        assert!(lower.stat("docs").await.unwrap().dir);
+        assert!(lower.stat("doc").await.is_err());
+        assert_eq!(
+            lower.read_range("docs/guide.md", 2..5).await.unwrap(),
+            b"gui"
+        );
+
+        let overlay = OverlayStorage::new(vec![Arc::new(upper), Arc::new(lower)]);
+        assert_eq!(read(&overlay, "index.html").await.unwrap(), b"upper");
+        assert_eq!(read(&overlay, "docs/guide.md").await.unwrap(), b"# guide");
+
+        let mut names: Vec<String> = overlay
+            .read_dir("")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        names.sort();
+        assert_eq!(names, ["docs", "index.html"]);
+    }
+
+    #[tokio::test]
+    async fn location_index_test() {
+        let storage = Arc::new(MemoryStorage::new());
+        storage.insert("a.txt", "aaa", 5);
+        storage.insert("B.txt", "b", 1);
+        storage.insert("c.txt", "cc", 9);
+        storage.insert("d/e.txt", "", 3);
+        // the site rule files are left out of the root listing only
+        storage.insert("_redirects", "", 2);
+        storage.insert("_headers", "", 2);
+        storage.insert("d/_headers", "", 2);
+
+        let list = |query: &str| {
+            let storage = storage.clone();
+            let options = IndexOptions::new(&query_params(query), None);
+            async move {
+                let index = location_index(storage, "", "", &LocationConfig::default(), options)
+                    .await
+                    .unwrap();
+                let streamed = matches!(&index, Index::Listing(l) if l.total().is_none());
+                let json: serde_json::Value = serde_json::from_str(&index.render().await).unwrap();
+                let names: Vec<String> = json["entries"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|entry| entry["name"].as_str().unwrap().to_owned())
+                    .collect();
+                (
+                    names,
+                    json["pages"].as_u64(),
+                    json["total"].as_u64(),
+                    streamed,
+                )
+            }
+        };
+
+        assert_eq!(
+            list("format=json").await,
+            (
+                vec!["a.txt".into(), "B.txt".into(), "c.txt".into(), "d".into()],
+                Some(1),
+                Some(4),
+                false
+            )
+        );
+        assert_eq!(
+            list("format=json&page=2&limit=3").await,
+            (vec!["d".into()], Some(2), Some(4), false)
+        );
+        assert_eq!(
+            list("format=json&sort=size&order=desc&limit=2").await,
+            (
+                vec!["a.txt".into(), "c.txt".into()],
+                Some(2),
+                Some(4),
+                false
+            )
+        );
+        assert_eq!(
+            list("format=json&sort=mtime&q=*.txt").await.0,
+            ["B.txt", "a.txt", "c.txt"]
+        );
+        assert_eq!(
+            list("format=json&page=9&limit=2").await.0,
+            Vec::<String>::new()
+        );
+        let options = IndexOptions::new(&query_params("format=json"), None);
+        let index = location_index(
+            storage.clone(),
+            "d",
+            "d",
+            &LocationConfig::default(),
+            options,
+        )
+        .await
+        .unwrap();
+        assert!(index.render().await.contains("\"_headers\""));
+
+        // longer listings are streamed unsorted, and still count every entry
+        CONFIG.rcu(|config| {
+            let mut config = (**config).clone();
+            config.server.cache = Some(CacheConfig {
+                index_maxentries: Some(2),
+                ..Default::default()
+            });
+            config
+        });
+        let (mut names, _, total, streamed) = list("format=json").await;
+        names.sort();
+        assert_eq!((names.len(), total, streamed), (4, Some(4), true));
+        assert!(!list("format=json&limit=2").await.3);
+    }
+
+    #[tokio::test]
+    async fn index_decorations_test() {
+        let storage = MemoryStorage::new();
+        storage.insert("README.md", "readme", 1);
+        storage.insert("d/x.md", "nested", 1);
+        storage.insert(".zest.yaml", "secret", 1);
+        storage.insert("big.md", vec![b'x'; 1024 * 1024 + 1], 1);
+
+        let readme = |names: &[&str], hide: &[&str]| {
+            let location_config = LocationConfig {
+                index_readme: Some(names.iter().map(|name| name.to_string()).collect()),
+                index_hide: Some(
+                    HidePatterns::new(hide.iter().map(|h| h.to_string()).collect()).unwrap(),
+                ),
+                ..Default::default()
+            };
+            let storage = &storage;
+            async move {
+                IndexDecorations::load(storage, "", "", &location_config)
+                    .await
+                    .readme
+            }
+        };
+
+        // only plain names of files a request could read are rendered
+        let names = [
+            "",
+            ".",
+            "..",
+            "../README.md",
+            "d/x.md",
+            ".zest.yaml",
+            "big.md",
+        ];
+        assert_eq!(readme(&names, &[]).await, None);
+        assert!(readme(&["big.md", "README.md"], &[])
+            .await
+            .is_some_and(|readme| readme.contains("readme")));
+        assert_eq!(readme(&["README.md"], &["*.md"]).await, None);
+    }
+
+    #[tokio::test]
+    async fn first_entries_test() {
+        let entries: Vec<IndexEntry> = (0..5000u64)
+            .map(|i| {
+                let meta = Metadata {
+                    size: i * 7919 % 5000,
+                    ..Default::default()
+                };
+                IndexEntry::new(i.to_string(), &meta)
+            })
+            .collect();
+        let (first, total) =
+            first_entries(stream::iter(entries), 10, |a, b| a.size.cmp(&b.size)).await;
+        assert_eq!(total, 5000);
+        assert_eq!(
+            first.iter().map(|e| e.size).collect::<Vec<_>>(),
+            (0..10).collect::<Vec<_>>()
+        );
+    }
+
+    #[cfg(feature = "archive")]
+    #[tokio::test]
+    async fn mounted_archive_test() {
+        use super::{archive::MountedArchive, storage::location_exists};
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+        use tokio::io::AsyncReadExt;
+        use zip::{write::SimpleFileOptions, ZipWriter};
+
+        let root = test_root().join("mount");
+        fs::create_dir_all(&root).unwrap();
+        let members = [
+            ("a/b.txt", "mounted"),
+            ("../out.txt", "x"),
+            ("a/../../up.txt", "x"),
+        ];
+
+        let mut zip = ZipWriter::new(fs::File::create(root.join("t.zip")).unwrap());
+        for (name, content) in members {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let mut tar = tar::Builder::new(vec![]);
+        for (name, content) in members {
+            // `set_path` refuses `..`, so write the name as an archive could
+            let mut header = tar::Header::new_gnu();
+            header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(content.len() as u64);
+            header.set_cksum();
+            tar.append(&header, content.as_bytes()).unwrap();
+        }
+        let tar = tar.into_inner().unwrap();
+        fs::write(root.join("t.tar"), &tar).unwrap();
+        let mut tgz = GzEncoder::new(vec![], Compression::fast());
+        tgz.write_all(&tar).unwrap();
+        fs::write(root.join("t.tgz"), tgz.finish().unwrap()).unwrap();
+
+        // redirects are shadowed by archive members too
+        assert!(location_exists("/mnt/a/b.txt").await);
+        assert!(!location_exists("/mnt/a/c.txt").await);
+
+        for name in ["t.zip", "t.tar", "t.tgz"] {
+            let archive = MountedArchive::open(&root.join(name)).unwrap();
+            let names = |dir: &'static str| {
+                let archive = &archive;
+                async move {
+                    let entries = archive.read_dir(dir).await.unwrap();
+                    entries.into_iter().map(|e| e.name).collect::<Vec<_>>()
+                }
+            };
+            assert_eq!(names("").await, ["a"], "{}", name);
+            assert_eq!(names("a").await, ["b.txt"], "{}", name);
+            assert!(archive.stat("a").await.unwrap().dir);
+            assert!(archive.stat("out.txt").await.is_err());
+            assert!(archive.stat("up.txt").await.is_err());
+
+            let mut content = String::new();
+            let mut reader = archive.open("a/b.txt").await.unwrap();
+            reader.read_to_string(&mut content).await.unwrap();
+            assert_eq!(content, "mounted", "{}", name);
+            assert_eq!(archive.read_range("a/b.txt", 2..5).await.unwrap(), b"unt");
+            assert_eq!(archive.read_range("a/b.txt", 5..99).await.unwrap(), b"ed");
+            assert!(archive.open("a").await.is_err());
+        }
+    }
+
+    #[cfg(feature = "archive")]
+    #[tokio::test]
+    async fn archive_entries_test() {
+        use super::archive::{archive_disposition, archive_entries, ArchiveFormat};
+
+        let root = test_root().join("arch");
+        write(&root.join("a.txt"), "a");
+        write(&root.join("b.tmp"), "b");
+        write(&root.join(".zest.yaml"), "auto_index: true");
+        write(&root.join("sub/c.txt"), "c");
+        let _ = symlink("/etc", root.join("out"));
+        write(&root.join("few/1.txt"), "1");
+        write(&root.join("few/2.txt"), "2");
+        write(&root.join("small/big.txt"), "more than ten bytes");
+        write(&root.join("_headers"), "# not a site rule file here");
+        write(&test_root().join("_redirects"), "# no rules");
+
+        let mut names: Vec<String> = archive_entries("arch/sub")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        names.sort();
+        assert_eq!(names, ["sub", "sub/c.txt"]);
+
+        let names: Vec<String> = archive_entries("/arch/")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert!(names.contains(&"arch/a.txt".to_owned()));
+        assert!(names.contains(&"arch/few/2.txt".to_owned()));
+        assert!(names.contains(&"arch/_headers".to_owned()));
+        for excluded in ["arch/b.tmp", "arch/.zest.yaml", "arch/out"] {
+            assert!(!names.contains(&excluded.to_owned()), "{}", excluded);
+        }
+
+        let names: Vec<String> = archive_entries("")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert!(names.contains(&"root/arch/a.txt".to_owned()));
+        assert!(!names.contains(&"root/_redirects".to_owned()));
+
+        assert!(archive_entries("arch/few").await.is_ok());
+        write(&root.join("few/3.txt"), "3");
+        assert_eq!(archive_entries("arch/few").await.err(), Some(413));
+        assert_eq!(archive_entries("arch/small").await.err(), Some(413));
+        assert_eq!(archive_entries("arch/missing").await.err(), Some(404));
+
+        assert_eq!(
+            archive_disposition("a/caf\u{e9}\r\n\"x", ArchiveFormat::Zip),
+            "attachment; filename=\"caf_x.zip\"; filename*=UTF-8''caf%C3%A9x.zip"
+        );
+    }
+
+    #[cfg(feature = "upload")]
+    #[tokio::test]
+    async fn multipart_test() {
+        use super::upload::Multipart;
+        use tokio::io::{duplex, AsyncWriteExt};
+
+        let body = b"preamble\r\n--b\r\n\
+Content-Disposition: form-data; name=\"f\"; filename=\"a.txt\"\r\n\r\n\
+hello\r\n--c\r\n\r\n--b\r\n\
+Content-Disposition: form-data; name=\"x\"\r\n\r\nvalue\r\n--b--\r\n";
+        // a tiny pipe hands the parser a few bytes at a time
+        let (mut client, server) = duplex(7);
+        tokio::spawn(async move { client.write_all(body).await });
+
+        let mut multipart = Multipart::new(server, "b");
+        let part = multipart.next_part().await.unwrap().unwrap();
+        assert_eq!(
+            part["content-disposition"],
+            "form-data; name=\"f\"; filename=\"a.txt\""
+        );
+        let mut content = vec![];
+        multipart.copy_to(&mut content).await.unwrap();
+        assert_eq!(content, b"hello\r\n--c\r\n");
+
+        assert!(multipart.next_part().await.unwrap().is_some());
+        assert!(multipart.next_part().await.unwrap().is_none());
+
+        let mut truncated = Multipart::new(&b"--b\r\nX: y\r\n\r\nabc"[..], "b");
+        assert!(truncated.next_part().await.unwrap().is_some());
+        assert_eq!(truncated.copy_to(&mut vec![]).await, Err(400));
+    }
+
+    #[cfg(feature = "upload")]
+    #[test]
+    fn upload_destination_test() {
+        use super::{config::OverwritePolicy, upload::destination};
+
+        let dir = temp_dir().join(format!("zest-destination-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a.txt"), "a").unwrap();
+        fs::write(dir.join("a (1).txt"), "a").unwrap();
+        fs::write(dir.join("Makefile"), "").unwrap();
+
+        let pick = |name, policy| destination(&dir, name, policy);
+        assert_eq!(
+            pick("b.txt", OverwritePolicy::Deny),
+            Ok(("b.txt".into(), false))
+        );
+        assert_eq!(pick("a.txt", OverwritePolicy::Deny), Err(409));
+        assert_eq!(
+            pick("a.txt", OverwritePolicy::Replace),
+            Ok(("a.txt".into(), true))
+        );
+        assert_eq!(
+            pick("a.txt", OverwritePolicy::Rename),
+            Ok(("a (2).txt".into(), false))
+        );
+        assert_eq!(
+            pick("Makefile", OverwritePolicy::Rename),
+            Ok(("Makefile (1)".into(), false))
+        );
+        assert_eq!(pick("sub", OverwritePolicy::Replace), Err(409));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "upload")]
+    #[tokio::test]
+    async fn upload_commit_test() {
+        use super::{config::OverwritePolicy, upload::stage};
+        use tokio::io::AsyncWriteExt;
+
+        let dir = test_root().join("commit");
+        fs::create_dir_all(&dir).unwrap();
+        let staged = |name: &'static str, policy| {
+            let dir = dir.clone();
+            async move {
+                stage(&dir, name, policy, async |file| {
+                    file.write_all(b"new").await.map_err(|_| 500)
+                })
+                .await
+                .unwrap()
+            }
+        };
+
+        // a file created while the body was written is never clobbered
+        let deny = staged("d.txt", OverwritePolicy::Deny).await;
+        write(&dir.join("d.txt"), "old");
+        assert_eq!(deny.commit().await, Err(409));
+        assert_eq!(fs::read_to_string(dir.join("d.txt")).unwrap(), "old");
+
+        let rename = staged("r.txt", OverwritePolicy::Rename).await;
+        write(&dir.join("r.txt"), "old");
+        assert_eq!(rename.commit().await, Ok(("r (1).txt".into(), false)));
+        assert_eq!(fs::read_to_string(dir.join("r (1).txt")).unwrap(), "new");
+
+        let replace = staged("d.txt", OverwritePolicy::Replace).await;
+        assert_eq!(replace.commit().await, Ok(("d.txt".into(), true)));
+        assert_eq!(fs::read_to_string(dir.join("d.txt")).unwrap(), "new");
+
+        // only the committed files are left, no temporary ones
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+    }
+
+    #[cfg(feature = "upload")]
+    #[tokio::test]
+    async fn upload_test() {
+        use super::upload::upload;
+        use std::collections::HashMap;
+        use tokio::io::{duplex, AsyncWriteExt, BufReader};
+
+        let dir = test_root().join("up");
+        fs::create_dir_all(&dir).unwrap();
+
+        let post = |files: &[&str], auth: &str| {
+            let mut body = String::new();
+            for file in files {
+                body.push_str(&format!(
+                    "--b\r\nContent-Disposition: form-data; name=\"f\"; filename=\"{}\"\r\n\r\n{}\r\n",
+                    file, file
+                ));
+            }
+            body.push_str("--b--\r\n");
+            let headers: HashMap<String, String> = [
+                ("content-type", "multipart/form-data; boundary=b".to_owned()),
+                ("content-length", body.len().to_string()),
+                ("authorization", format!("Basic {}", auth)),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_owned(), v))
+            .collect();
+            async move {
+                let (mut client, mut server) = duplex(64 * 1024);
+                client.write_all(body.as_bytes()).await.unwrap();
+                let location_config = location_config("/up/").unwrap_or_default();
+                let reader = &mut BufReader::new(&mut server);
+                upload("POST", "/up/", &location_config, &headers, reader).await
+            }
+        };
+        let names = || {
+            let mut names: Vec<String> = fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            names
+        };
+
+        // "u:pa" and "u:p"
+        assert_eq!(post(&["a.txt"], "dTpwYQ==").await, Err(401));
+        assert_eq!(post(&["a.txt", "b.exe"], "dTpw").await, Err(415));
+        assert!(names().is_empty());
+        assert_eq!(
+            post(&["a.txt", "a.txt"], "dTpw").await,
+            Ok((303, "/up/".into()))
+        );
+        assert_eq!(names(), ["a (1).txt", "a.txt"]);
+    }
+
+    #[cfg(feature = "webdav")]
+    #[test]
+    fn webdav_parse_test() {
+        use super::webdav::{
+            destination_location, is_under, parse_lockinfo, parse_propfind, parse_proppatch,
+            PropFind, PropName,
+        };
+
+        let prop = |ns: &str, name: &str| PropName {
+            ns: ns.to_owned(),
+            name: name.to_owned(),
+        };
+        assert_eq!(parse_propfind(""), Ok(PropFind::AllProp));
+        assert_eq!(
+            parse_propfind(r#"<propfind xmlns="DAV:"><propname/></propfind>"#),
+            Ok(PropFind::PropName)
+        );
+        assert_eq!(
+            parse_propfind(
+                r#"<D:propfind xmlns:D="DAV:"><D:prop><D:getetag/><x:a xmlns:x="urn:x"/></D:prop></D:propfind>"#
+            ),
+            Ok(PropFind::Prop(vec![
+                prop("DAV:", "getetag"),
+                prop("urn:x", "a")
+            ]))
+        );
+        assert_eq!(parse_propfind("<propfind/>"), Err(400));
+        assert_eq!(parse_propfind("<D:propfind xmlns:D=\"DAV:\">"), Err(400));
+
+        assert_eq!(
+            parse_proppatch(
+                r#"<D:propertyupdate xmlns:D="DAV:" xmlns:x="urn:x"><D:set><D:prop><x:a>1</x:a></D:prop></D:set><D:remove><D:prop><x:b/></D:prop></D:remove></D:propertyupdate>"#
+            ),
+            Ok(vec![
+                (prop("urn:x", "a"), Some("1".to_owned())),
+                (prop("urn:x", "b"), None)
+            ])
+        );
+        assert_eq!(
+            parse_proppatch(r#"<propertyupdate xmlns="DAV:"/>"#),
+            Err(400)
+        );
+
+        assert_eq!(
+            parse_lockinfo(
+                r#"<D:lockinfo xmlns:D="DAV:"><D:lockscope><D:shared/></D:lockscope><D:locktype><D:write/></D:locktype><D:owner>me</D:owner></D:lockinfo>"#
+            ),
+            Ok((false, Some("me".to_owned())))
+        );
+        assert_eq!(
+            parse_lockinfo(
+                r#"<lockinfo xmlns="DAV:"><lockscope><exclusive/></lockscope></lockinfo>"#
+            ),
+            Ok((true, None))
+        );
+        assert_eq!(parse_lockinfo("<lock/>"), Err(400));
+
+        assert!(is_under("a/b", "a"));
+        assert!(is_under("a", ""));
+        assert!(!is_under("ab", "a"));
+        assert!(!is_under("a", "a"));
+        assert_eq!(
+            destination_location("http://host:80/a%20b/./c?x=1"),
+            Some("a b/c".to_owned())
+        );
+        assert_eq!(destination_location("/a/../b/"), Some("b".to_owned()));
+        assert_eq!(destination_location("/../b"), None);
+    }
+
+    #[cfg(feature = "webdav")]
+    #[tokio::test]
+    async fn webdav_test() {
+        use super::webdav::{check_locks, webdav};
+        use std::collections::HashMap;
+        use tokio::io::{duplex, AsyncWriteExt, BufReader};
+
+        let root = test_root();
+        write(&root.join("dav1/a.txt"), "hello");
+        write(&root.join("dav1/d/p.txt"), "p");
+        write(&root.join("dav2/b.txt"), "bye");
+        fs::create_dir_all(root.join("dav3")).unwrap();
+
+        let dav = |method: &str, path: &str, headers: &[(&str, &str)], body: &str| {
+            let mut headers: HashMap<String, String> = headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            headers.insert("content-length".to_owned(), body.len().to_string());
+            let (method, path, body) = (method.to_owned(), path.to_owned(), body.to_owned());
+            async move {
+                let (mut client, mut server) = duplex(64 * 1024);
+                client.write_all(body.as_bytes()).await.unwrap();
+                let location_config = location_config(&path).unwrap_or_default();
+                let reader = &mut BufReader::new(&mut server);
+                webdav(&method, &path, &location_config, &headers, reader)
+                    .await
+                    .map(|response| (response.status_code, response.headers, response.body))
+            }
+        };
+        let status = |result: Result<(i32, _, _), i32>| result.map_or_else(|e| e, |r| r.0);
+        // "a:1" and "b:2"
+        let a = ("authorization", "Basic YTox");
+        let b = ("authorization", "Basic Yjoy");
+
+        // both locations must let the client in, and the destination's size applies
+        let to = |path| ("destination", path);
+        assert_eq!(
+            status(dav("COPY", "/dav1/a.txt", &[a, to("/dav2/a.txt")], "").await),
+            401
+        );
+        assert_eq!(
+            status(dav("COPY", "/dav2/b.txt", &[b, to("/dav1/b.txt")], "").await),
+            401
+        );
+        assert_eq!(
+            status(dav("COPY", "/dav1/a.txt", &[a, to("/dav3/a.txt")], "").await),
+            413
+        );
+        assert!(!root.join("dav2/a.txt").exists() && !root.join("dav1/b.txt").exists());
+
+        // roots can be neither removed nor replaced
+        assert_eq!(status(dav("DELETE", "/dav1", &[a], "").await), 403);
+        assert_eq!(
+            status(dav("MOVE", "/dav1", &[a, to("/dav3/x")], "").await),
+            403
+        );
+        assert_eq!(
+            status(dav("COPY", "/dav1/d", &[a, to("/dav3")], "").await),
+            403
+        );
+
+        // neither a copy nor a move may replace the source's parent
+        for method in ["COPY", "MOVE"] {
+            let parent = [a, to("/dav1/d")];
+            assert_eq!(status(dav(method, "/dav1/d/p.txt", &parent, "").await), 403);
+            assert!(root.join("dav1/d/p.txt").exists(), "{}", method);
+        }
+
+        // dead properties follow a moved tree
+        let set = r#"<D:propertyupdate xmlns:D="DAV:" xmlns:x="urn:x"><D:set><D:prop><x:a>v</x:a></D:prop></D:set></D:propertyupdate>"#;
+        assert_eq!(
+            status(dav("PROPPATCH", "/dav1/d/p.txt", &[a], set).await),
+            207
+        );
+        assert_eq!(
+            status(dav("MOVE", "/dav1/d", &[a, to("/dav3/d")], "").await),
+            201
+        );
+        let find =
+            r#"<D:propfind xmlns:D="DAV:"><D:prop><x:a xmlns:x="urn:x"/></D:prop></D:propfind>"#;
+        let found = dav("PROPFIND", "/dav3/d/p.txt", &[a, ("depth", "0")], find).await;
+        assert!(found.unwrap().2.unwrap().contains(">v</x:a>"));
+
+        // a lock holds its resource and, deeply, its ancestors
+        let info = r#"<D:lockinfo xmlns:D="DAV:"><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockinfo>"#;
+        let (code, headers, _) = dav("LOCK", "/dav1/a.txt", &[a], info).await.unwrap();
+        assert_eq!(code, 200);
+        let token = headers[0].1.clone();
+        let none = HashMap::new();
+        let held: HashMap<String, String> = [("if".to_owned(), format!("({})", token))].into();
+        assert_eq!(check_locks("dav1/a.txt", &none, false), Err(423));
+        assert_eq!(check_locks("dav1/a.txt", &held, false), Ok(()));
+        assert_eq!(check_locks("dav1", &none, true), Err(423));
+        assert_eq!(check_locks("dav1", &none, false), Ok(()));
+        assert_eq!(status(dav("LOCK", "/dav1/a.txt", &[a], info).await), 423);
+        let unlock = ("lock-token", token.as_str());
+        assert_eq!(
+            status(dav("UNLOCK", "/dav1/a.txt", &[a, unlock], "").await),
+            204
+        );
+        assert_eq!(check_locks("dav1/a.txt", &none, false), Ok(()));
+    }
 }
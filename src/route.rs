@@ -1,39 +1,1050 @@
-use crate::config::{LocationConfig, CONFIG};
-use anyhow::{anyhow, Context, Result};
-use serde_yml::from_value;
+use crate::{
+    config::{dir_config, ErrorPage, Location, LocationConfig, SymlinkPolicy, CONFIG, DIR_CONFIG},
+    storage::{
+        is_partial_upload, is_site_rules, local_metadata, location_storage, read, Metadata,
+        Storage, StorageEntry,
+    },
+};
+use anyhow::{anyhow, Result};
+use chrono::DateTime;
+use futures::{
+    future,
+    stream::{self, BoxStream},
+    Stream, StreamExt,
+};
+use globset::{Glob, GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+use mime::Mime;
+use serde::{Deserialize, Serialize};
 use std::{
+    cmp::Ordering,
+    collections::HashMap,
     fmt::Write,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
 };
-use tokio::fs::{self, read_dir, DirEntry};
+use tokio::fs;
+
+#[cfg(feature = "index_template")]
+use crate::init::TEMPLATES;
+
+#[cfg(target_os = "android")]
+use std::os::android::fs::MetadataExt;
+
+#[cfg(target_os = "linux")]
+use std::os::linux::fs::MetadataExt;
+
+/// Entries rendered per chunk when a listing is streamed.
+const INDEX_CHUNK: usize = 1024;
+
+/// Header, README and footer files larger than this are not rendered.
+const MAX_DECORATION_SIZE: u64 = 1024 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IndexFormat {
+    Html,
+    Json,
+    Text,
+}
+
+impl IndexFormat {
+    /// Picks the listing format from `?format=`, falling back to the supported
+    /// media type the `Accept` header weighs highest. Ties go to HTML, then
+    /// JSON, and `q=0` rules a type out.
+    pub fn negotiate(format: Option<&str>, accept: Option<&str>) -> Self {
+        match format {
+            Some("json") => return IndexFormat::Json,
+            Some("text") | Some("plain") => return IndexFormat::Text,
+            Some("html") => return IndexFormat::Html,
+            _ => {}
+        }
+
+        let accept = accept.unwrap_or("*/*");
+        // the q of the most specific range matching `media_type`
+        let quality = |media_type: &str| {
+            let mut best: Option<(u8, f32)> = None;
+            for range in accept.split(',') {
+                let mut params = range.split(';');
+                let range = params.next().unwrap_or_default().trim();
+                let specificity = match range.strip_suffix("/*") {
+                    _ if range == media_type => 2,
+                    Some("*") => 0,
+                    Some(kind) if media_type.split('/').next() == Some(kind) => 1,
+                    _ => continue,
+                };
+                let q = params
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .and_then(|q| q.trim().parse().ok())
+                    .unwrap_or(1.0);
+                if best.is_none_or(|(most, _)| specificity > most) {
+                    best = Some((specificity, q));
+                }
+            }
+            best.map(|(_, q)| q)
+        };
+
+        [
+            (IndexFormat::Html, "text/html"),
+            (IndexFormat::Json, "application/json"),
+            (IndexFormat::Text, "text/plain"),
+        ]
+        .into_iter()
+        .filter_map(|(format, media_type)| Some((format, quality(media_type)?)))
+        .filter(|(_, q)| *q > 0.0)
+        .fold(
+            None,
+            |best: Option<(IndexFormat, f32)>, (format, q)| match best {
+                Some((_, most)) if most >= q => best,
+                _ => Some((format, q)),
+            },
+        )
+        .map_or(IndexFormat::Html, |(format, _)| format)
+    }
+
+    #[inline]
+    pub fn mime(&self) -> Mime {
+        match self {
+            IndexFormat::Html => mime::TEXT_HTML_UTF_8,
+            IndexFormat::Json => mime::APPLICATION_JSON,
+            IndexFormat::Text => mime::TEXT_PLAIN_UTF_8,
+        }
+    }
+
+    #[inline]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IndexFormat::Html => "html",
+            IndexFormat::Json => "json",
+            IndexFormat::Text => "text",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SortKey {
+    #[default]
+    Name,
+    Size,
+    Mtime,
+}
+
+impl SortKey {
+    #[inline]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Mtime => "mtime",
+        }
+    }
+}
+
+/// Per-request listing options taken from the query string and headers.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct IndexOptions {
+    pub format: IndexFormat,
+    pub sort: SortKey,
+    pub desc: bool,
+    pub page: usize,
+    pub limit: Option<usize>,
+    pub filter: Option<String>,
+}
+
+impl IndexOptions {
+    pub fn new(params: &HashMap<String, String>, accept: Option<&str>) -> Self {
+        IndexOptions {
+            format: IndexFormat::negotiate(params.get("format").map(|s| s.as_str()), accept),
+            sort: match params.get("sort").map(|s| s.as_str()) {
+                Some("size") => SortKey::Size,
+                Some("mtime") => SortKey::Mtime,
+                _ => SortKey::Name,
+            },
+            desc: params.get("order").is_some_and(|order| order == "desc"),
+            page: params
+                .get("page")
+                .and_then(|page| page.parse().ok())
+                .unwrap_or(1)
+                .max(1),
+            limit: params
+                .get("limit")
+                .and_then(|limit| limit.parse().ok())
+                .filter(|limit| *limit > 0),
+            filter: params
+                .get("filter")
+                .or_else(|| params.get("q"))
+                .filter(|filter| !filter.is_empty())
+                .cloned(),
+        }
+    }
+
+    /// Link to this listing with another sort order or page.
+    fn href(&self, sort: SortKey, desc: bool, page: usize) -> String {
+        let mut href = format!(
+            "?sort={}&amp;order={}",
+            sort.as_str(),
+            if desc { "desc" } else { "asc" }
+        );
+        if page > 1 {
+            write!(href, "&amp;page={}", page).unwrap();
+        }
+        if let Some(limit) = self.limit {
+            write!(href, "&amp;limit={}", limit).unwrap();
+        }
+        if let Some(filter) = &self.filter {
+            write!(href, "&amp;q={}", urlencoding::encode(filter)).unwrap();
+        }
+        href
+    }
+
+    #[inline]
+    fn order(&self) -> &'static str {
+        if self.desc {
+            "desc"
+        } else {
+            "asc"
+        }
+    }
+
+    /// Key of the rendered listing in `INDEX_CACHE`, for the directory found
+    /// in the root layer `layer`.
+    #[inline]
+    pub fn cache_key(&self, layer: &str, location: &str) -> String {
+        format!(
+            "{}:{}:{}:{}:{}:{}:{}:{}",
+            self.format.as_str(),
+            self.sort.as_str(),
+            self.order(),
+            self.page,
+            self.limit.unwrap_or_default(),
+            urlencoding::encode(self.filter.as_deref().unwrap_or_default()),
+            layer,
+            location
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct IndexEntry {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub size: u64,
+    pub mtime: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mime: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+impl IndexEntry {
+    /// Describes the entry `name` of a listing from its storage metadata.
+    pub fn new(name: String, meta: &Metadata) -> Self {
+        IndexEntry {
+            mime: (!meta.dir).then(|| {
+                meta.mime
+                    .clone()
+                    .unwrap_or_else(|| mime_match(&name))
+                    .to_string()
+            }),
+            kind: match (&meta.symlink, meta.dir) {
+                (Some(_), _) => "symlink",
+                (None, true) => "dir",
+                (None, false) => "file",
+            },
+            name,
+            size: meta.size,
+            mtime: meta.mtime,
+            target: meta.symlink.clone(),
+        }
+    }
+
+    #[inline]
+    pub fn href(&self) -> String {
+        let href = urlencoding::encode(&self.name).into_owned();
+        if self.kind == "dir" {
+            href + "/"
+        } else {
+            href
+        }
+    }
+
+    /// Whether the entry is a directory or a symlink to one, which are the
+    /// entries without a MIME type.
+    #[inline]
+    pub fn is_dir(&self) -> bool {
+        self.mime.is_none()
+    }
+
+    #[inline]
+    pub fn etag(&self) -> String {
+        format!("\"{:x}-{:x}\"", self.mtime, self.size)
+    }
+
+    #[inline]
+    pub fn display_name(&self) -> String {
+        match self.kind {
+            "dir" => format!("{}/", self.name),
+            "symlink" => format!("{}@", self.name),
+            _ => self.name.clone(),
+        }
+    }
+
+    #[inline]
+    pub fn display_size(&self) -> String {
+        if self.kind == "dir" {
+            "-".to_owned()
+        } else {
+            human_size(self.size)
+        }
+    }
+
+    #[inline]
+    pub fn display_mtime(&self) -> String {
+        DateTime::from_timestamp(self.mtime, 0)
+            .map(|mtime| mtime.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Serialize)]
+pub struct Breadcrumb {
+    pub name: String,
+    pub href: String,
+}
+
+/// Rendered HEADER, README and FOOTER files of a listed directory.
+#[derive(Serialize, Default)]
+pub struct IndexDecorations {
+    pub header: Option<String>,
+    pub readme: Option<String>,
+    pub footer: Option<String>,
+}
+
+impl IndexDecorations {
+    pub async fn load(
+        storage: &dyn Storage,
+        path: &str,
+        location: &str,
+        location_config: &LocationConfig,
+    ) -> Self {
+        let markdown = location_config.index_markdown.unwrap_or(true);
+        let names = |names: &Option<Vec<String>>, defaults: &[&str]| {
+            names
+                .clone()
+                .unwrap_or_else(|| defaults.iter().map(|s| s.to_string()).collect())
+        };
+
+        IndexDecorations {
+            header: index_decoration(
+                storage,
+                path,
+                location,
+                location_config,
+                &names(&location_config.index_header, &["HEADER.html", "HEADER.md"]),
+                markdown,
+            )
+            .await,
+            readme: index_decoration(
+                storage,
+                path,
+                location,
+                location_config,
+                &names(&location_config.index_readme, &["README.md", "README.html"]),
+                markdown,
+            )
+            .await,
+            footer: index_decoration(
+                storage,
+                path,
+                location,
+                location_config,
+                &names(&location_config.index_footer, &["FOOTER.html", "FOOTER.md"]),
+                markdown,
+            )
+            .await,
+        }
+    }
+}
+
+/// A listing rendered from a user template or an index file.
+pub enum Index {
+    Rendered(String),
+    Listing(Listing),
+}
+
+impl Index {
+    pub async fn render(self) -> String {
+        match self {
+            Index::Rendered(body) => body,
+            Index::Listing(listing) => listing.into_chunks().collect().await,
+        }
+    }
+}
+
+/// One page of a directory listing, rendered lazily so that huge directories
+/// can be streamed chunk by chunk.
+pub struct Listing {
+    location: String,
+    options: IndexOptions,
+    entries: Vec<IndexEntry>,
+    /// The entries still to be read, in directory order, for listings too
+    /// long to sort in memory.
+    rest: Option<BoxStream<'static, IndexEntry>>,
+    /// Entries matching the listing, counting the ones still to be read.
+    total: usize,
+    decorations: IndexDecorations,
+    archive: bool,
+    upload: bool,
+}
+
+impl Listing {
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty() && self.rest.is_none()
+    }
+
+    /// Entries matching the listing, known once it is read to the end.
+    #[inline]
+    pub fn total(&self) -> Option<usize> {
+        self.rest.is_none().then_some(self.total)
+    }
+
+    #[inline]
+    fn pages(&self) -> usize {
+        self.options
+            .limit
+            .map_or(1, |limit| self.total.div_ceil(limit).max(1))
+    }
+
+    /// Renders the head, the entries in groups of `INDEX_CHUNK`, reading the
+    /// rest of a streamed listing as it goes, and the tail.
+    pub fn into_chunks(self) -> BoxStream<'static, String> {
+        enum Part {
+            Head,
+            Rows(usize),
+            Rest,
+            Tail,
+        }
+
+        stream::unfold(Some((self, Part::Head)), |state| async move {
+            let (mut listing, part) = state?;
+            let (chunk, next) = match part {
+                Part::Head => (listing.head(), Part::Rows(0)),
+                Part::Rows(start) if start < listing.entries.len() => {
+                    let end = (start + INDEX_CHUNK).min(listing.entries.len());
+                    let rows = listing.rows(&listing.entries[start..end], start == 0);
+                    (rows, Part::Rows(end))
+                }
+                Part::Rows(_) | Part::Rest => {
+                    let chunk = match listing.rest.as_mut() {
+                        Some(rest) => rest.take(INDEX_CHUNK).collect::<Vec<_>>().await,
+                        None => Vec::new(),
+                    };
+                    if chunk.is_empty() {
+                        listing.rest = None;
+                        (listing.tail(), Part::Tail)
+                    } else {
+                        let rows = listing.rows(&chunk, listing.total == 0);
+                        listing.total += chunk.len();
+                        (rows, Part::Rest)
+                    }
+                }
+                Part::Tail => return None,
+            };
+            let state = (!matches!(next, Part::Tail)).then_some((listing, next));
+            Some((chunk, state))
+        })
+        .boxed()
+    }
+
+    fn head(&self) -> String {
+        match self.options.format {
+            IndexFormat::Html => index_html_head(
+                &self.location,
+                &self.options,
+                &self.decorations,
+                self.archive,
+                self.upload,
+            ),
+            IndexFormat::Json => format!(
+                "{{\"path\":{},\"page\":{},\"pages\":{},\"entries\":[",
+                serde_json::to_string(&format!("/{}", self.location)).unwrap(),
+                self.options.page,
+                self.pages(),
+            ),
+            IndexFormat::Text => String::new(),
+        }
+    }
+
+    fn rows(&self, entries: &[IndexEntry], first: bool) -> String {
+        let mut rows = String::with_capacity(entries.len() * 128);
+        for (i, entry) in entries.iter().enumerate() {
+            match self.options.format {
+                IndexFormat::Html => writeln!(
+                    rows,
+                    "<tr><td><a href=\"{href}\">{name}</a></td><td class=\"size\">{size}</td><td>{mtime}</td></tr>",
+                    href = entry.href(),
+                    name = html_escape(&entry.display_name()),
+                    size = entry.display_size(),
+                    mtime = entry.display_mtime(),
+                ),
+                IndexFormat::Json => write!(
+                    rows,
+                    "{}{}",
+                    if first && i == 0 { "" } else { "," },
+                    serde_json::to_string(entry).unwrap()
+                ),
+                IndexFormat::Text => writeln!(
+                    rows,
+                    "{}{}",
+                    entry.name,
+                    if entry.kind == "dir" { "/" } else { "" }
+                ),
+            }
+            .unwrap();
+        }
+        rows
+    }
+
+    fn tail(&self) -> String {
+        match self.options.format {
+            IndexFormat::Html => {
+                let mut pager = String::new();
+                let pages = self.pages();
+                if pages > 1 {
+                    let link = |page: usize, label: &str| {
+                        format!(
+                            "<a href=\"{}\">{}</a>",
+                            self.options
+                                .href(self.options.sort, self.options.desc, page),
+                            label
+                        )
+                    };
+                    pager.push_str("<p>");
+                    if self.options.page > 1 {
+                        pager.push_str(&link(self.options.page - 1, "&laquo; Previous"));
+                        pager.push(' ');
+                    }
+                    write!(pager, "Page {} of {}", self.options.page, pages).unwrap();
+                    if self.options.page < pages {
+                        pager.push(' ');
+                        pager.push_str(&link(self.options.page + 1, "Next &raquo;"));
+                    }
+                    pager.push_str("</p>\n");
+                }
+
+                format!(
+                    "</tbody>
+</table>
+{pager}{readme}<hr>
+{footer}</body>
+</html>
+",
+                    readme = self.decorations.readme.as_deref().unwrap_or_default(),
+                    footer = self.decorations.footer.as_deref().unwrap_or_default(),
+                )
+            }
+            // streamed listings only know their total at the end
+            IndexFormat::Json => format!("],\"total\":{}}}", self.total),
+            IndexFormat::Text => String::new(),
+        }
+    }
+}
 
 #[inline]
 pub fn root_relative(p: &str) -> &str {
     p.trim_start_matches('/')
 }
 
+/// Splits a query string into decoded `key=value` pairs.
+pub fn query_params(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+            let decode = |s: &str| {
+                urlencoding::decode(&s.replace('+', " "))
+                    .map(|s| s.into_owned())
+                    .unwrap_or_default()
+            };
+            (decode(k), decode(v))
+        })
+        .collect()
+}
+
 #[inline]
-pub async fn location_index(path: PathBuf, location: &str) -> Result<String> {
+pub fn html_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The settings for `location`, inherited down the locations covering it
+/// and tweaked by the `.zest.yaml` files of its directories.
+pub fn location_config(location: &str) -> Option<LocationConfig> {
+    let mut config = CONFIG
+        .load()
+        .locations
+        .as_ref()
+        .and_then(|locations| locations.inherited(location));
+
+    let Ok(location) = normalize_location(location) else {
+        return config;
+    };
+    let parts: Vec<&str> = location
+        .split('/')
+        .filter(|part| !part.is_empty())
+        .collect();
+    for depth in 0..=parts.len() {
+        if let Some(dir_config) = dir_config(&parts[..depth].join("/")) {
+            config = Some(dir_config.apply(config.unwrap_or_default()));
+        }
+    }
+    config
+}
+
+/// The location chosen for `location` by the compiled `locations`.
+pub fn location_match(location: &str) -> Option<Arc<Location>> {
+    CONFIG.load().locations.as_ref()?.find(location).cloned()
+}
+
+/// Names of the index files tried in order for a directory: the location's
+/// `index`, else `server.index`, else `index.html`.
+pub fn index_files(location_config: &LocationConfig) -> Vec<String> {
     let config = CONFIG.load();
+    match location_config
+        .index
+        .as_ref()
+        .or(config.server.index.as_ref())
+    {
+        Some(index) => index
+            .files()
+            .iter()
+            .filter_map(|file| file.to_str())
+            .map(|file| file.trim_matches('/').to_owned())
+            .filter(|file| !file.is_empty())
+            .collect(),
+        None => vec!["index.html".to_owned()],
+    }
+}
+
+/// Compiled `index_hide` glob patterns, matched against entry names. The
+/// `.zest.yaml` override files and partial uploads are always hidden.
+///
+/// They are compiled when the config is read, which fails on a bad pattern,
+/// and shared by every request after that.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(try_from = "Vec<String>", into = "Vec<String>")]
+pub struct HidePatterns {
+    patterns: Vec<String>,
+    /// One set per list joined by `and`, so that nothing is recompiled.
+    sets: Vec<Arc<GlobSet>>,
+}
+
+impl HidePatterns {
+    pub fn new(patterns: Vec<String>) -> Result<Self, globset::Error> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in &patterns {
+            builder.add(Glob::new(pattern)?);
+        }
+        Ok(HidePatterns {
+            patterns,
+            sets: vec![Arc::new(builder.build()?)],
+        })
+    }
 
-    for (s, v) in &config.locations.clone().unwrap_or_default() {
-        if root_relative(s) == location.trim_end_matches('/') {
-            if let Ok(_location) = from_value::<LocationConfig>(v.clone()) {
-                if let Some(index) = _location.index {
-                    let path = PathBuf::from(location).join(index.clone());
-                    let _path = root_relative(path.as_path().to_str().unwrap());
-
-                    return fs::read_to_string(_path)
-                        .await
-                        .with_context(move || format!("failed to read path {}", _path));
-                } else if _location.auto_index.is_none() || !_location.auto_index.unwrap() {
-                    return Err(anyhow!("Index not supported"));
+    /// These patterns followed by `other`'s.
+    pub fn and(mut self, other: &HidePatterns) -> Self {
+        self.patterns.extend(other.patterns.iter().cloned());
+        self.sets.extend(other.sets.iter().cloned());
+        self
+    }
+
+    #[inline]
+    pub fn is_match(&self, name: &str) -> bool {
+        name == DIR_CONFIG
+            || is_partial_upload(name)
+            || self.sets.iter().any(|set| set.is_match(name))
+    }
+
+    /// Whether any component of `location` is hidden.
+    pub fn is_hidden(&self, location: &str) -> bool {
+        location
+            .split('/')
+            .filter(|part| !part.is_empty())
+            .any(|part| self.is_match(part))
+    }
+}
+
+impl TryFrom<Vec<String>> for HidePatterns {
+    type Error = globset::Error;
+
+    fn try_from(patterns: Vec<String>) -> Result<Self, Self::Error> {
+        HidePatterns::new(patterns)
+    }
+}
+
+impl From<HidePatterns> for Vec<String> {
+    fn from(hide: HidePatterns) -> Self {
+        hide.patterns
+    }
+}
+
+/// A `?filter=` or `?q=` query, a glob when it holds wildcards and a
+/// case-insensitive substring otherwise.
+pub enum NameFilter {
+    Glob(GlobMatcher),
+    Substring(String),
+}
+
+impl NameFilter {
+    pub fn new(filter: &str) -> Self {
+        if filter.contains(['*', '?', '[']) {
+            if let Ok(glob) = GlobBuilder::new(filter).case_insensitive(true).build() {
+                return NameFilter::Glob(glob.compile_matcher());
+            }
+        }
+        NameFilter::Substring(filter.to_lowercase())
+    }
+
+    #[inline]
+    pub fn is_match(&self, name: &str) -> bool {
+        match self {
+            NameFilter::Glob(glob) => glob.is_match(name),
+            NameFilter::Substring(filter) => name.to_lowercase().contains(filter),
+        }
+    }
+}
+
+/// Lexically resolves `.` and `..` in a decoded request path.
+///
+/// Fails with 403 when `..` climbs above the root.
+pub fn normalize_location(location: &str) -> Result<String, i32> {
+    let mut parts: Vec<&str> = vec![];
+    for component in Path::new(root_relative(location)).components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str().ok_or(400)?),
+            Component::ParentDir => {
+                parts.pop().ok_or(403)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(parts.join("/"))
+}
+
+/// Resolves a decoded request path against `root`, keeping it inside the root.
+///
+/// Returns the status code to answer with when the path is missing (404),
+/// hidden (404) or leaves the root through `..` or a disallowed symlink (403).
+pub fn resolve_path(
+    root: &Path,
+    location: &str,
+    symlinks: SymlinkPolicy,
+    deny_hidden: bool,
+) -> Result<PathBuf, i32> {
+    let root = root.canonicalize().map_err(|_| 404)?;
+
+    let location = normalize_location(location)?;
+    let parts: Vec<&str> = location
+        .split('/')
+        .filter(|part| !part.is_empty())
+        .collect();
+
+    if deny_hidden && parts.iter().any(|part| part.starts_with('.')) {
+        return Err(404);
+    }
+
+    let mut path = root.clone();
+    for part in parts {
+        path.push(part);
+        let meta = path.symlink_metadata().map_err(|_| 404)?;
+        if meta.file_type().is_symlink() {
+            match symlinks {
+                SymlinkPolicy::Follow => {}
+                SymlinkPolicy::OwnerMatch => {
+                    let target = path.metadata().map_err(|_| 404)?;
+                    if target.st_uid() != meta.st_uid() {
+                        return Err(403);
+                    }
                 }
+                SymlinkPolicy::Deny => return Err(403),
+            }
+        }
+    }
+
+    let path = path.canonicalize().map_err(|_| 404)?;
+    if path.starts_with(&root) {
+        Ok(path)
+    } else {
+        Err(403)
+    }
+}
+
+/// Whether an entry of the directory at `location` is listed, leaving out
+/// names hidden by `index_hide`, dotfiles under `deny_hidden`, the site rule
+/// files at the root and names not matching `filter`.
+fn entry_filter(
+    location: &str,
+    location_config: &LocationConfig,
+    filter: Option<NameFilter>,
+) -> impl Fn(&StorageEntry) -> bool + Send + Sync + 'static {
+    let deny_hidden = CONFIG.load().server.deny_hidden.unwrap_or(false);
+    let hide = location_config.index_hide.clone().unwrap_or_default();
+    let site_root = location.trim_matches('/').is_empty();
+
+    move |entry| {
+        let hidden = hide.is_match(&entry.name)
+            || (deny_hidden && entry.name.starts_with('.'))
+            || (site_root && is_site_rules(&entry.name));
+        !hidden && filter.as_ref().is_none_or(|f| f.is_match(&entry.name))
+    }
+}
+
+/// Reads the listed entries of the directory at `path`, served at `location`.
+pub async fn read_entries(
+    storage: &dyn Storage,
+    path: &str,
+    location: &str,
+    location_config: &LocationConfig,
+    filter: Option<NameFilter>,
+) -> Result<Vec<IndexEntry>> {
+    let listed = entry_filter(location, location_config, filter);
+    Ok(storage
+        .read_dir(path)
+        .await?
+        .into_iter()
+        .filter(|entry| listed(entry))
+        .map(|entry| IndexEntry::new(entry.name, &entry.meta))
+        .collect())
+}
+
+/// Orders entries by `sort`, directories and files alike.
+fn entry_order(sort: SortKey, desc: bool) -> impl Fn(&IndexEntry, &IndexEntry) -> Ordering + Copy {
+    move |a, b| {
+        let order = match sort {
+            SortKey::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Mtime => a.mtime.cmp(&b.mtime),
+        };
+        if desc {
+            order.reverse()
+        } else {
+            order
+        }
+    }
+}
+
+/// The first `n` entries by `order` and how many entries there were, holding
+/// no more than about twice `n` of them at a time.
+pub async fn first_entries<S, F>(mut entries: S, n: usize, order: F) -> (Vec<IndexEntry>, usize)
+where
+    S: Stream<Item = IndexEntry> + Unpin,
+    F: Fn(&IndexEntry, &IndexEntry) -> Ordering + Copy,
+{
+    let (mut first, mut total) = (Vec::new(), 0);
+    while let Some(entry) = entries.next().await {
+        total += 1;
+        if n == 0 {
+            continue;
+        }
+        first.push(entry);
+        if first.len() >= n.max(INDEX_CHUNK) * 2 {
+            first.select_nth_unstable_by(n - 1, order);
+            first.truncate(n);
+        }
+    }
+    first.sort_by(order);
+    first.truncate(n);
+    (first, total)
+}
+
+pub async fn location_index(
+    storage: Arc<dyn Storage>,
+    path: &str,
+    location: &str,
+    location_config: &LocationConfig,
+    mut options: IndexOptions,
+) -> Result<Index> {
+    if !location_config.auto_index.unwrap_or(true) {
+        return Err(anyhow!("Index not supported"));
+    }
+
+    // archives and embedded trees are only listed when asked to
+    let virtual_root = location_config.archive_root.is_some() || location_config.embed.is_some();
+    if virtual_root && !location_config.auto_index.unwrap_or(false) {
+        return Err(anyhow!("Index not supported"));
+    }
+
+    let listed = entry_filter(
+        location,
+        location_config,
+        options.filter.as_deref().map(NameFilter::new),
+    );
+    let mut entries = storage
+        .clone()
+        .entries(path.to_owned())
+        .await?
+        .filter(move |entry| future::ready(listed(entry)))
+        .map(|entry| IndexEntry::new(entry.name, &entry.meta))
+        .boxed();
+
+    options.limit = options
+        .limit
+        .or(location_config.index_limit)
+        .filter(|limit| *limit > 0);
+    let order = entry_order(options.sort, options.desc);
+
+    // only the entries up to the requested page are kept, and listings too
+    // long to sort in memory are streamed in directory order instead
+    let (index_entries, rest, total) = match options.limit {
+        Some(limit) => {
+            let start = (options.page - 1).saturating_mul(limit);
+            let (mut first, total) =
+                first_entries(entries, start.saturating_add(limit), order).await;
+            first.drain(..start.min(first.len()));
+            (first, None, total)
+        }
+        None => {
+            let max = CONFIG
+                .load()
+                .server
+                .cache
+                .as_ref()
+                .and_then(|cache| cache.index_maxentries)
+                .unwrap_or(10000);
+            let mut first: Vec<IndexEntry> = entries.by_ref().take(max + 1).collect().await;
+            let total = first.len();
+            if total > max {
+                (first, Some(entries), total)
+            } else {
+                first.sort_by(order);
+                (first, None, total)
             }
         }
+    };
+
+    let decorations = if options.format == IndexFormat::Html {
+        IndexDecorations::load(storage.as_ref(), path, location, location_config).await
+    } else {
+        IndexDecorations::default()
+    };
+
+    let listing = Listing {
+        location: location.to_owned(),
+        options: options.clone(),
+        entries: index_entries,
+        rest,
+        total,
+        decorations,
+        archive: !virtual_root
+            && cfg!(feature = "archive")
+            && location_config.archive.unwrap_or(false),
+        upload: !virtual_root && cfg!(feature = "upload") && location_config.upload.is_some(),
+    };
+
+    match (&location_config.index_template, options.format) {
+        // templates render whole pages, so streamed listings keep the built-in one
+        #[cfg(feature = "index_template")]
+        (Some(template), IndexFormat::Html) if listing.rest.is_none() => {
+            let entries: Vec<TemplateEntry> = listing
+                .entries
+                .iter()
+                .map(|entry| TemplateEntry {
+                    entry,
+                    href: entry.href(),
+                    display_name: entry.display_name(),
+                    display_size: entry.display_size(),
+                    display_mtime: entry.display_mtime(),
+                })
+                .collect();
+
+            render_template(
+                &CONFIG.load().server.root.join(template),
+                IndexContext {
+                    location: format!("/{}", location),
+                    breadcrumbs: breadcrumbs(location),
+                    entries,
+                    sort: options.sort.as_str(),
+                    order: options.order(),
+                    page: options.page,
+                    pages: listing.pages(),
+                    total,
+                    archive: listing.archive,
+                    upload: listing.upload,
+                    decorations: &listing.decorations,
+                },
+            )
+            .await
+            .map(Index::Rendered)
+        }
+        _ => Ok(Index::Listing(listing)),
     }
+}
+
+/// Links to every parent of `location`, starting with the root.
+pub fn breadcrumbs(location: &str) -> Vec<Breadcrumb> {
+    let mut breadcrumbs = vec![Breadcrumb {
+        name: "/".to_owned(),
+        href: "/".to_owned(),
+    }];
 
-    let mut entries = read_dir(path.clone()).await?;
+    let mut href = String::from("/");
+    for part in location.split('/').filter(|part| !part.is_empty()) {
+        href.push_str(&urlencoding::encode(part));
+        href.push('/');
+        breadcrumbs.push(Breadcrumb {
+            name: part.to_owned(),
+            href: href.clone(),
+        });
+    }
+    breadcrumbs
+}
+
+fn index_html_head(
+    location: &str,
+    options: &IndexOptions,
+    decorations: &IndexDecorations,
+    archive: bool,
+    upload: bool,
+) -> String {
+    let title = html_escape(&format!("/{}", location));
+
+    let mut nav = String::new();
+    for (i, breadcrumb) in breadcrumbs(location).iter().enumerate() {
+        let separator = if i == 0 { "" } else { "/" };
+        write!(
+            nav,
+            "<a href=\"{}\">{}</a>{}",
+            breadcrumb.href,
+            html_escape(&breadcrumb.name),
+            separator
+        )
+        .unwrap();
+    }
+
+    let column = |key: SortKey, label: &str| {
+        let desc = options.sort == key && !options.desc;
+        format!(
+            "<th><a href=\"{}\">{}</a></th>",
+            options.href(key, desc, 1),
+            label
+        )
+    };
 
     let mut html: String = String::with_capacity(1024);
     html.push_str(&format!(
@@ -41,47 +1052,330 @@ pub async fn location_index(path: PathBuf, location: &str) -> Result<String> {
 <html lang=\"en\">
 <head>
 <meta charset=\"utf-8\">
-<title>Directory listing for /{location}</title>
+<title>Directory listing for {title}</title>
+<style>
+th, td {{ padding: 0 1em 0 0; text-align: left; }}
+td.size {{ text-align: right; }}
+</style>
 </head>
 <body>
-<h1>Directory listing for /{location}</h1>
-<hr>
-<ul>
-"
+<h1>Directory listing for {nav}</h1>
+<form><input type=\"search\" name=\"q\" placeholder=\"Filter\" value=\"{filter}\"></form>
+{download}{upload}<hr>
+{header}<table>
+<thead>
+<tr>{name}{size}{mtime}</tr>
+</thead>
+<tbody>
+",
+        name = column(SortKey::Name, "Name"),
+        size = column(SortKey::Size, "Size"),
+        mtime = column(SortKey::Mtime, "Last modified"),
+        header = decorations.header.as_deref().unwrap_or_default(),
+        filter = html_escape(options.filter.as_deref().unwrap_or_default()),
+        download = if archive {
+            "<p>Download: <a href=\"?archive=zip\">.zip</a> <a href=\"?archive=tar.gz\">.tar.gz</a></p>\n"
+        } else {
+            ""
+        },
+        upload = if upload {
+            "<form method=\"post\" enctype=\"multipart/form-data\"><input type=\"file\" name=\"file\" multiple> <input type=\"submit\" value=\"Upload\"></form>\n"
+        } else {
+            ""
+        },
     ));
 
-    #[allow(unused_mut)]
-    let mut entries_vec: Vec<DirEntry> = vec![];
-
-    while let Some(entry) = entries.next_entry().await? {
-        entries_vec.push(entry);
+    if !location.trim_matches('/').is_empty() {
+        html.push_str(
+            "<tr><td><a href=\"../\">../</a></td><td class=\"size\">-</td><td>-</td></tr>\n",
+        );
     }
-    #[cfg(feature = "index_sort")]
+
+    html
+}
+
+#[cfg(feature = "index_template")]
+#[derive(Serialize)]
+struct TemplateEntry<'a> {
+    #[serde(flatten)]
+    entry: &'a IndexEntry,
+    href: String,
+    display_name: String,
+    display_size: String,
+    display_mtime: String,
+}
+
+#[cfg(feature = "index_template")]
+#[derive(Serialize)]
+struct IndexContext<'a> {
+    location: String,
+    breadcrumbs: Vec<Breadcrumb>,
+    entries: Vec<TemplateEntry<'a>>,
+    sort: &'static str,
+    order: &'static str,
+    page: usize,
+    pages: usize,
+    total: usize,
+    archive: bool,
+    upload: bool,
+    #[serde(flatten)]
+    decorations: &'a IndexDecorations,
+}
+
+/// Renders a listing through a user template, compiling it on first use.
+///
+/// Templates stay compiled in `TEMPLATES` until the next SIGHUP.
+#[cfg(feature = "index_template")]
+async fn render_template<C: Serialize>(path: &Path, ctx: C) -> Result<String> {
+    let name = path.display().to_string();
+
     {
-        entries_vec.sort_by(|a, b| {
-            a.file_name()
-                .to_ascii_lowercase()
-                .cmp(&b.file_name().to_ascii_lowercase())
+        let env = TEMPLATES.read().await;
+        if let Ok(template) = env.get_template(&name) {
+            return Ok(template.render(ctx)?);
+        }
+    }
+
+    let source = fs::read_to_string(path).await.map_err(|e| {
+        minijinja::Error::new(
+            minijinja::ErrorKind::TemplateNotFound,
+            format!("failed to read template {}: {}", name, e),
+        )
+    })?;
+
+    let mut env = TEMPLATES.write().await;
+    env.add_template_owned(name.clone(), source)?;
+    Ok(env.get_template(&name)?.render(ctx)?)
+}
+
+/// Renders the first of `names` found in the listed directory at `location`
+/// to HTML.
+///
+/// Only plain file names are looked up, and only files a request could read
+/// and the listing would show, up to `MAX_DECORATION_SIZE`. Markdown is
+/// converted when enabled, and with the `markdown` feature the output is
+/// sanitized so that a dropped-in file cannot inject scripts.
+async fn index_decoration(
+    storage: &dyn Storage,
+    path: &str,
+    location: &str,
+    location_config: &LocationConfig,
+    names: &[String],
+    markdown: bool,
+) -> Option<String> {
+    let hide = location_config.index_hide.clone().unwrap_or_default();
+    let location = location.trim_matches('/');
+    let path = path.trim_end_matches('/');
+
+    for name in names {
+        if name.is_empty()
+            || name.contains('/')
+            || name == "."
+            || name == ".."
+            || hide.is_match(name)
+        {
+            continue;
+        }
+        let requested = if location.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", location, name)
+        };
+        if location_storage(&requested).await.is_err() {
+            continue;
+        }
+
+        let name = if path.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", path, name)
+        };
+        match storage.stat(&name).await {
+            Ok(meta) if !meta.dir && meta.size <= MAX_DECORATION_SIZE => {}
+            _ => continue,
+        }
+        let Ok(content) = read(storage, &name).await else {
+            continue;
+        };
+        let Ok(content) = String::from_utf8(content) else {
+            continue;
+        };
+
+        let is_markdown = matches!(
+            Path::new(&name).extension().and_then(|ext| ext.to_str()),
+            Some("md") | Some("markdown")
+        );
+        return Some(match (is_markdown, markdown) {
+            (true, true) => render_markdown(&content),
+            (true, false) => format!("<pre>{}</pre>", html_escape(&content)),
+            _ => sanitize_html(&content),
         });
     }
+    None
+}
+
+#[cfg(feature = "markdown")]
+fn render_markdown(content: &str) -> String {
+    let mut html = String::with_capacity(content.len());
+    pulldown_cmark::html::push_html(&mut html, pulldown_cmark::Parser::new(content));
+    sanitize_html(&html)
+}
+
+#[cfg(not(feature = "markdown"))]
+fn render_markdown(content: &str) -> String {
+    format!("<pre>{}</pre>", html_escape(content))
+}
+
+#[cfg(feature = "markdown")]
+fn sanitize_html(content: &str) -> String {
+    ammonia::clean(content)
+}
 
-    for entry in entries_vec {
-        process_entry(&mut html, &entry, &path).await;
+/// Without ammonia there is nothing to clean HTML with, so it is shown as text.
+#[cfg(not(feature = "markdown"))]
+fn sanitize_html(content: &str) -> String {
+    format!("<pre>{}</pre>", html_escape(content))
+}
+
+/// Formats a byte count with binary units, e.g. `1.5 KiB`.
+pub fn human_size(size: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
     }
 
-    html.push_str(
-        "</ul>
-<hr>
-</body>
-</html>
-",
-    );
+    if unit == 0 {
+        format!("{} {}", size, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Resolves a `try_files` list for `location` with `resolve`.
+///
+/// Every entry but the last is tried in order, `$uri` expanding to the request
+/// path and a trailing `/` asking for a directory instead of a file. The last
+/// entry is either a `=code` status or a fallback URI. Returns the location that
+/// matched along with its path.
+pub fn try_files<F>(
+    candidates: &[String],
+    location: &str,
+    resolve: F,
+) -> Result<(String, PathBuf), i32>
+where
+    F: Fn(&str) -> Result<PathBuf, i32>,
+{
+    let uri = format!("/{}", root_relative(location));
+    let Some((fallback, candidates)) = candidates.split_last() else {
+        return resolve(location).map(|path| (location.to_owned(), path));
+    };
+
+    let expand = |candidate: &str| candidate.replace("$uri", &uri).replace("//", "/");
 
-    Ok(html)
+    for candidate in candidates {
+        let expanded = expand(candidate);
+        if let Ok(path) = resolve(&expanded) {
+            if path.is_dir() == candidate.ends_with('/') {
+                return Ok((root_relative(&expanded).to_owned(), path));
+            }
+        }
+    }
+
+    match fallback.strip_prefix('=') {
+        Some(status_code) => Err(status_code.parse().unwrap_or(404)),
+        None => {
+            let expanded = expand(fallback);
+            resolve(&expanded).map(|path| (root_relative(&expanded).to_owned(), path))
+        }
+    }
+}
+
+/// Renders the custom error page for `status_code`, if one is configured.
+///
+/// Location `error_pages` take precedence over the server ones, exact codes over
+/// ranges such as `5xx`, and the legacy `error_page` only applies to 404.
+/// `{name}` placeholders in the page are replaced by the escaped `vars`.
+pub async fn error_page(
+    status_code: i32,
+    location_config: &LocationConfig,
+    vars: &[(&str, &str)],
+) -> Option<String> {
+    let config = CONFIG.load();
+
+    let page = location_config
+        .error_pages
+        .as_ref()
+        .and_then(|pages| match_error_page(pages, status_code))
+        .or_else(|| {
+            config
+                .server
+                .error_pages
+                .as_ref()
+                .and_then(|pages| match_error_page(pages, status_code))
+        })
+        .or_else(|| {
+            (status_code == 404)
+                .then(|| config.server.error_page.clone().map(ErrorPage::File))
+                .flatten()
+        })?;
+
+    let mut template = match page {
+        ErrorPage::File(path) => fs::read_to_string(config.server.root.join(path))
+            .await
+            .ok()?,
+        ErrorPage::Template { template } => template,
+    };
+
+    for (name, value) in vars {
+        template = template.replace(&format!("{{{}}}", name), &html_escape(value));
+    }
+
+    Some(template)
 }
 
 #[inline]
-pub async fn status_page(status: &str, info: String) -> String {
+fn match_error_page(pages: &HashMap<String, ErrorPage>, status_code: i32) -> Option<ErrorPage> {
+    let code = status_code.to_string();
+    let range = format!("{}xx", &code[..1]);
+
+    pages
+        .iter()
+        .find(|(k, _)| **k == code)
+        .or_else(|| pages.iter().find(|(k, _)| k.eq_ignore_ascii_case(&range)))
+        .map(|(_, page)| page.clone())
+}
+
+#[inline]
+pub async fn status_page(status_code: i32, status: &str, info: String) -> String {
+    let message = match status_code {
+        301 => "The requested resource has moved.",
+        303 => "The upload is complete.",
+        400 => "The server could not understand the request.",
+        401 => "Authentication is required to access this resource.",
+        403 => "You don't have permission to access this resource.",
+        404 => "The requested resource was not found on this server.",
+        405 => "The request method is not allowed for this resource.",
+        409 => "The request conflicts with the current state of the resource.",
+        410 => "The requested resource is no longer available.",
+        411 => "The request must specify its Content-Length.",
+        412 => "A precondition of the request was not met.",
+        413 => "The request body is larger than the server is willing to accept.",
+        414 => "The request URI is longer than the server is willing to interpret.",
+        415 => "The uploaded file type is not allowed.",
+        416 => "The requested range cannot be satisfied.",
+        423 => "The requested resource is locked.",
+        424 => "The request failed because a related operation failed.",
+        429 => "Too many requests, please try again later.",
+        431 => "The request header fields are too large.",
+        501 => "The request method is not supported by the server.",
+        503 => "The server is temporarily unable to handle the request.",
+        _ => "The server encountered an internal error.", // 500
+    };
+
     format!(
         "<html>
 <head>
@@ -90,6 +1384,7 @@ pub async fn status_page(status: &str, info: String) -> String {
 <body>
     <center>
         <h1>{status}</h1>
+        <p>{message}</p>
     </center>
     <hr>
     <center>{info}</center>
@@ -99,32 +1394,9 @@ pub async fn status_page(status: &str, info: String) -> String {
     )
 }
 
-#[inline]
-async fn process_entry(html: &mut String, entry: &DirEntry, path: &Path) {
-    let meta = entry.metadata().await.unwrap();
-    let mut linkname = entry
-        .path()
-        .strip_prefix(path)
-        .unwrap()
-        .display()
-        .to_string();
-
-    let displayname = if meta.is_dir() {
-        linkname = format!("{}/", linkname);
-        linkname.clone()
-    } else if meta.is_symlink() {
-        format!("{}@", linkname)
-    } else {
-        linkname.clone()
-    };
-
-    writeln!(
-        html,
-        "<li><a href=\"{linkname}\">{displayname}</a></li>",
-        linkname = linkname,
-        displayname = displayname
-    )
-    .unwrap();
+/// Describes the file, directory or symlink at `path` as a listing entry.
+pub async fn index_entry(path: &Path, name: String) -> Result<IndexEntry> {
+    Ok(IndexEntry::new(name, &local_metadata(path).await?))
 }
 
 #[inline]
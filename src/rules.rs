@@ -0,0 +1,299 @@
+use crate::{
+    route::{query_params, root_relative},
+    server::reason,
+    storage::resolve_root,
+};
+use arc_swap::ArcSwap;
+use lazy_static::lazy_static;
+use std::{collections::HashMap, fs, sync::Arc};
+
+#[cfg(feature = "log")]
+use log::logger;
+
+pub const REDIRECTS: &str = "_redirects";
+pub const HEADERS: &str = "_headers";
+
+lazy_static! {
+    /// The `_redirects` and `_headers` rules of the site root, reloaded on SIGHUP.
+    pub static ref SITE_RULES: ArcSwap<SiteRules> = ArcSwap::from_pointee(SiteRules::default());
+}
+
+/// A path of `_redirects` and `_headers`, made of literal and `:placeholder`
+/// segments and optionally ending in a `*` splat.
+#[derive(Debug)]
+struct PathPattern {
+    segments: Vec<Segment>,
+    splat: bool,
+}
+
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    Placeholder(String),
+}
+
+impl PathPattern {
+    fn new(pattern: &str) -> Result<Self, String> {
+        if !pattern.starts_with('/') {
+            return Err(format!("path {} does not start with /", pattern));
+        }
+        let parts: Vec<&str> = pattern.split('/').filter(|s| !s.is_empty()).collect();
+        let splat = parts.last() == Some(&"*");
+        let mut segments = Vec::new();
+        for part in &parts[..parts.len() - splat as usize] {
+            if part.contains('*') {
+                return Err(format!("splat in {} is not at the end", pattern));
+            }
+            segments.push(match part.strip_prefix(':') {
+                Some(name) => Segment::Placeholder(name.to_owned()),
+                None => Segment::Literal(part.to_string()),
+            });
+        }
+        Ok(PathPattern { segments, splat })
+    }
+
+    /// The placeholders and `splat` captured from `path`, if it matches.
+    fn captures(&self, path: &str) -> Option<HashMap<String, String>> {
+        let parts: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        if parts.len() < self.segments.len() || (!self.splat && parts.len() > self.segments.len()) {
+            return None;
+        }
+        let mut captures = HashMap::new();
+        for (segment, part) in self.segments.iter().zip(&parts) {
+            match segment {
+                Segment::Literal(literal) if literal != part => return None,
+                Segment::Literal(_) => {}
+                Segment::Placeholder(name) => {
+                    captures.insert(name.clone(), part.to_string());
+                }
+            }
+        }
+        if self.splat {
+            captures.insert("splat".to_owned(), parts[self.segments.len()..].join("/"));
+        }
+        Some(captures)
+    }
+}
+
+/// A `from [key=value...] to [status][!]` line of `_redirects`.
+#[derive(Debug)]
+struct Redirect {
+    from: PathPattern,
+    /// Query parameters the request must have, with a literal value or a
+    /// `:placeholder` capturing it.
+    query: Vec<(String, String)>,
+    to: String,
+    status_code: i32,
+    force: bool,
+}
+
+impl Redirect {
+    fn new(line: &str) -> Result<Self, String> {
+        let mut tokens = line.split_whitespace().peekable();
+        let from = PathPattern::new(tokens.next().unwrap_or_default())?;
+        let mut query = Vec::new();
+        while let Some(token) = tokens.next_if(|token| {
+            token.contains('=') && !token.starts_with('/') && !token.contains("://")
+        }) {
+            let (key, value) = token.split_once('=').unwrap();
+            query.push((key.to_owned(), value.to_owned()));
+        }
+        let to = tokens.next().ok_or("missing destination")?.to_owned();
+
+        let (status_code, force) = match tokens.next() {
+            None => (301, false),
+            Some(status) => {
+                let force = status.ends_with('!');
+                let status_code = status
+                    .trim_end_matches('!')
+                    .parse::<i32>()
+                    .map_err(|_| format!("invalid status {}", status))?;
+                (status_code, force)
+            }
+        };
+        match status_code {
+            301 | 302 | 303 | 307 | 308 => {}
+            200 | 400..=499 if to.contains("://") => {
+                return Err(format!("cannot proxy to {}", to));
+            }
+            200 => {}
+            400..=499 if reason(status_code) != reason(500) => {}
+            _ => return Err(format!("unsupported status {}", status_code)),
+        }
+        if let Some(condition) = tokens.next() {
+            return Err(format!("unsupported condition {}", condition));
+        }
+
+        Ok(Redirect {
+            from,
+            query,
+            to,
+            status_code,
+            force,
+        })
+    }
+
+    /// `to` with the placeholders replaced by what `from` and `query` captured.
+    fn target(&self, path: &str, params: &HashMap<String, String>) -> Option<String> {
+        let mut captures = self.from.captures(path)?;
+        for (key, value) in &self.query {
+            let param = params.get(key)?;
+            match value.strip_prefix(':') {
+                Some(name) => {
+                    captures.insert(name.to_owned(), param.clone());
+                }
+                None if param != value => return None,
+                None => {}
+            }
+        }
+
+        let mut target = String::new();
+        let mut rest = self.to.as_str();
+        while let Some(start) = rest.find(':') {
+            target.push_str(&rest[..start]);
+            let name: String = rest[start + 1..]
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+                .collect();
+            match captures.get(&name) {
+                Some(value) => target.push_str(value),
+                None => target.push_str(&rest[start..start + 1 + name.len()]),
+            }
+            rest = &rest[start + 1 + name.len()..];
+        }
+        target.push_str(rest);
+        Some(target)
+    }
+}
+
+/// What a `_redirects` rule does with a request.
+#[derive(Debug, PartialEq)]
+pub enum SiteAction {
+    /// Answers with the status and a `Location` header.
+    Redirect(i32, String),
+    /// Serves another location, with the status if it is found.
+    Rewrite(i32, String),
+}
+
+#[derive(Debug, Default)]
+pub struct SiteRules {
+    redirects: Vec<Redirect>,
+    headers: Vec<(PathPattern, Vec<(String, String)>)>,
+}
+
+impl SiteRules {
+    /// Parses `_redirects` and `_headers`, skipping the lines that fail along
+    /// with their line numbers and errors.
+    pub fn parse(redirects: &str, headers: &str) -> (Self, Vec<(&'static str, usize, String)>) {
+        let mut rules = SiteRules::default();
+        let mut errors = Vec::new();
+        let lines = |file: &'static str, content: &str| {
+            content
+                .lines()
+                .enumerate()
+                .filter(|(_, line)| !line.trim().is_empty() && !line.trim().starts_with('#'))
+                .map(|(i, line)| (file, i + 1, line.to_owned()))
+                .collect::<Vec<_>>()
+        };
+
+        for (file, n, line) in lines(REDIRECTS, redirects) {
+            match Redirect::new(&line) {
+                Ok(redirect) => rules.redirects.push(redirect),
+                Err(e) => errors.push((file, n, e)),
+            }
+        }
+
+        let mut valid = false;
+        for (file, n, line) in lines(HEADERS, headers) {
+            if !line.starts_with(char::is_whitespace) {
+                valid = match PathPattern::new(line.trim()) {
+                    Ok(pattern) => {
+                        rules.headers.push((pattern, Vec::new()));
+                        true
+                    }
+                    Err(e) => {
+                        errors.push((file, n, e));
+                        false
+                    }
+                };
+            } else if let Some((name, value)) = line.split_once(':') {
+                if !valid {
+                    errors.push((file, n, "header outside of a path".to_owned()));
+                    continue;
+                }
+                let (_, block) = rules.headers.last_mut().unwrap();
+                let (name, value) = (name.trim(), value.trim());
+                match block.iter_mut().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+                    Some((_, values)) => *values = format!("{}, {}", values, value),
+                    None => block.push((name.to_owned(), value.to_owned())),
+                }
+            } else {
+                errors.push((file, n, format!("invalid header {}", line.trim())));
+            }
+        }
+
+        (rules, errors)
+    }
+
+    /// The first redirect matching the request `target`. Rules without `!` are
+    /// shadowed by an existing file, as reported by `exists`.
+    pub async fn action<F>(&self, target: &str, exists: F) -> Option<SiteAction>
+    where
+        F: AsyncFn(&str) -> bool,
+    {
+        let (target_path, query) = target.split_once('?').unwrap_or((target, ""));
+        let path = urlencoding::decode(target_path).ok()?;
+        let params = query_params(query);
+
+        let redirect = self
+            .redirects
+            .iter()
+            .find_map(|redirect| Some((redirect, redirect.target(&path, &params)?)));
+        let (redirect, to) = redirect?;
+        if !redirect.force && exists(root_relative(&path)).await {
+            return None;
+        }
+
+        // the query passes on unless the rule matched on it
+        if (300..400).contains(&redirect.status_code) {
+            let to = if to.contains('?') || query.is_empty() || !redirect.query.is_empty() {
+                to
+            } else {
+                format!("{}?{}", to, query)
+            };
+            Some(SiteAction::Redirect(redirect.status_code, to))
+        } else {
+            let to = to.split_once('?').map_or(to.as_str(), |(to, _)| to);
+            Some(SiteAction::Rewrite(
+                redirect.status_code,
+                root_relative(to).to_owned(),
+            ))
+        }
+    }
+
+    /// The headers of every `_headers` block matching `path`, in file order.
+    pub fn headers(&self, path: &str) -> Vec<(&str, &str)> {
+        self.headers
+            .iter()
+            .filter(|(pattern, _)| pattern.captures(path).is_some())
+            .flat_map(|(_, headers)| headers.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+            .collect()
+    }
+}
+
+/// Reads `_redirects` and `_headers` from the site root into `SITE_RULES`,
+/// logging the lines that fail to parse.
+pub fn load_site_rules() {
+    let read = |file| {
+        resolve_root(file)
+            .ok()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .unwrap_or_default()
+    };
+    let (rules, _errors) = SiteRules::parse(&read(REDIRECTS), &read(HEADERS));
+    #[cfg(feature = "log")]
+    for (file, line, e) in _errors {
+        error!("{}:{}: {}", file, line, e);
+    }
+    SITE_RULES.store(Arc::new(rules));
+}
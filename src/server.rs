@@ -1,11 +1,23 @@
 use crate::{
-    config::{init_config, Config, ARGS, CONFIG, CONFIG_PATH, DEFAULT_CONFIG, DEFAULT_INTERVAL},
+    config::{
+        load_config, CacheConfig, Config, LocationConfig, ARGS, CONFIG, CONFIG_PATH,
+        DEFAULT_CONFIG, DEFAULT_INTERVAL, DIR_CONFIGS,
+    },
     init::{DATE_FORMAT, PID_FILE},
-    route::{location_index, mime_match, root_relative, status_page},
+    route::{
+        error_page, index_files, location_config, location_index, mime_match, normalize_location,
+        query_params, root_relative, status_page, try_files, Index, IndexOptions, Listing,
+    },
+    rules::{load_site_rules, SiteAction, SITE_RULES},
+    storage::{
+        dir_prefix, error_status, location_exists, location_storage, read, resolve_root, Metadata,
+        Storage, PASSWD_HOMES,
+    },
 };
 
 use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
+use futures::StreamExt;
 use mime::Mime;
 use signal_hook::{
     consts::{SIGHUP, SIGINT},
@@ -17,15 +29,35 @@ use std::{
     error::Error,
     fs::{self, remove_file},
     io,
-    num::NonZero,
-    ops::Deref,
-    path::Path,
+    ops::{Deref, Range},
     process,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
 };
 
 #[cfg(feature = "lru_cache")]
-use crate::init::{init_cache, FILE_CACHE, INDEX_CACHE};
+use crate::init::{
+    file_budget, index_budget, init_cache, CLEAN_URL_CACHE, FILE_CACHE, INDEX_CACHE,
+};
+
+#[cfg(feature = "index_template")]
+use crate::init::{init_templates, TEMPLATES};
+
+#[cfg(feature = "upload")]
+use crate::upload::upload;
+
+#[cfg(feature = "webdav")]
+use crate::webdav::webdav;
+
+#[cfg(feature = "archive")]
+use {
+    crate::archive::{
+        archive_disposition, archive_entries, archive_stream, ArchiveFormat, MOUNTED_ARCHIVES,
+    },
+    tokio::sync::mpsc,
+};
 
 #[cfg(feature = "log")]
 use {
@@ -33,15 +65,9 @@ use {
     log::logger,
 };
 
-#[cfg(target_os = "android")]
-use std::os::android::fs::MetadataExt;
-
-#[cfg(target_os = "linux")]
-use std::os::linux::fs::MetadataExt;
-
 use tokio::{
     fs::File,
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
     net::TcpListener,
     sync::{
         oneshot::{self, Receiver, Sender},
@@ -50,6 +76,21 @@ use tokio::{
     time::sleep,
 };
 
+const MAX_REQUEST_LINE: usize = 8192;
+
+/// Requests with more header lines, or longer ones, are answered with 431.
+const MAX_HEADERS: usize = 100;
+const MAX_HEADER_LINE: usize = 8192;
+
+static REQUEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A response body written out chunk by chunk instead of from `buffer`.
+enum Streamed {
+    Listing(Listing),
+    #[cfg(feature = "archive")]
+    Archive(mpsc::Receiver<io::Result<Vec<u8>>>),
+}
+
 #[derive(Clone)]
 struct Response<'a> {
     version: &'a str,
@@ -58,12 +99,19 @@ struct Response<'a> {
 }
 
 impl<'a> Response<'a> {
+    /// Sets header `k`, replacing any value it had under another case.
     #[inline]
     fn send_header<T>(&mut self, k: &'a str, v: T) -> Option<String>
     where
         T: ToString,
     {
-        self._headers_buffer.insert(k, v.to_string())
+        let same = self
+            ._headers_buffer
+            .keys()
+            .find(|key| key.eq_ignore_ascii_case(k))
+            .copied();
+        let previous = same.and_then(|key| self._headers_buffer.remove(key));
+        self._headers_buffer.insert(k, v.to_string()).or(previous)
     }
     #[inline]
     fn resp(&mut self) -> String {
@@ -77,25 +125,258 @@ impl<'a> Response<'a> {
     }
     #[inline]
     fn status(&mut self, status_code: i32) -> String {
-        let status = match status_code {
-            200 => "OK",
-            301 => "Moved Permanently",
-            400 => "Bad Request",
-            404 => "Not Found",
-            501 => "Not Implemented",
-            _ => "Internal Server Error", // 500
-        };
+        format!("{} {}", status_code, reason(status_code))
+    }
+}
+
+/// The reason phrase of a status code.
+pub fn reason(status_code: i32) -> &'static str {
+    match status_code {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        207 => "Multi-Status",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        423 => "Locked",
+        424 => "Failed Dependency",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error", // 500
+    }
+}
+
+/// The decoded path of the request target, without the query.
+fn request_location(req: &str) -> String {
+    let target = req.split_whitespace().nth(1).unwrap_or_default();
+    let (target_path, _) = target.split_once('?').unwrap_or((target, ""));
+    urlencoding::decode(root_relative(target_path))
+        .unwrap_or_default()
+        .into()
+}
+
+/// A request path found in the storage serving it.
+struct Found {
+    location: String,
+    /// Whether `clean_urls` found it as `location.html`.
+    clean: bool,
+    /// The config of the location chosen for it.
+    location_config: LocationConfig,
+    storage: Arc<dyn Storage>,
+    path: String,
+    meta: Metadata,
+}
+
+/// Looks `location`, which `location_config` applies to, up in its storage.
+/// Directories give way to their first index file when `index` is set.
+async fn locate(
+    location: &str,
+    index: bool,
+    location_config: LocationConfig,
+) -> Result<Found, i32> {
+    let (storage, path) = location_storage(location).await?;
+    let meta = storage.stat(&path).await.map_err(|e| error_status(&e))?;
+
+    if meta.dir && index {
+        for name in index_files(&location_config) {
+            let index = format!("{}{}", dir_prefix(&path), name);
+            if let Some(index_meta) = storage.stat(&index).await.ok().filter(|meta| !meta.dir) {
+                return Ok(Found {
+                    location: format!("{}{}", dir_prefix(location.trim_end_matches('/')), name),
+                    clean: false,
+                    location_config,
+                    storage,
+                    path: index,
+                    meta: index_meta,
+                });
+            }
+        }
+    }
+
+    Ok(Found {
+        location: location.to_owned(),
+        clean: false,
+        location_config,
+        storage,
+        path,
+        meta,
+    })
+}
+
+/// Like `locate`, falling back to `location.html` for `clean_urls`. Which
+/// of the two serves a location, or that neither does, is remembered so
+/// that later requests skip the lookups.
+async fn locate_clean(
+    location: &str,
+    index: bool,
+    location_config: LocationConfig,
+) -> Result<Found, i32> {
+    let html = format!("{}.html", location.trim_end_matches('/'));
+    let clean = |found: Found| Found {
+        clean: true,
+        ..found
+    };
+
+    #[cfg(feature = "lru_cache")]
+    match CLEAN_URL_CACHE.write().await.get(location).copied() {
+        Some(false) => return Err(404),
+        Some(true) => match locate(&html, false, location_config.clone()).await {
+            Ok(found) if !found.meta.dir => return Ok(clean(found)),
+            _ => {
+                CLEAN_URL_CACHE.write().await.pop(location);
+            }
+        },
+        None => {}
+    }
+
+    match locate(location, index, location_config.clone()).await {
+        Err(404) if !location.trim_matches('/').is_empty() && !location.ends_with(".html") => {
+            let found = locate(&html, false, location_config)
+                .await
+                .ok()
+                .filter(|found| !found.meta.dir);
+            #[cfg(feature = "lru_cache")]
+            CLEAN_URL_CACHE
+                .write()
+                .await
+                .push(location.to_owned(), found.is_some());
+            found.map(clean).ok_or(404)
+        }
+        found => found,
+    }
+}
+
+/// Maps a failed listing to its status code, logging template errors.
+/// Storage errors keep their own status and anything else is a disabled
+/// listing.
+fn index_error(e: anyhow::Error) -> i32 {
+    #[cfg(feature = "index_template")]
+    if let Some(_err) = e.downcast_ref::<minijinja::Error>() {
+        #[cfg(feature = "log")]
+        error!("failed to render index template: {}", _err);
+        return 500;
+    }
 
-        format!("{} {}", status_code, status)
+    match e.downcast_ref::<io::Error>() {
+        Some(e) => error_status(e),
+        None => 403,
     }
 }
 
-async fn handle_connection<S>(mut stream: S) -> Result<(i32, String)>
+/// The tag of a file's gzip variant, `etag` with `-gz` inside its quotes.
+pub fn gzip_etag(etag: &str) -> String {
+    match etag.strip_suffix('"') {
+        Some(tag) => format!("{}-gz\"", tag),
+        None => format!("{}-gz", etag),
+    }
+}
+
+/// The bytes a `Range` header asks for out of `size`, `None` when it is not a
+/// single byte range and the whole file is served instead, or 416 when the
+/// range lies beyond the end.
+pub fn byte_range(range: &str, size: u64) -> Result<Option<Range<u64>>, i32> {
+    let Some((start, end)) = range
+        .trim()
+        .strip_prefix("bytes=")
+        .filter(|spec| !spec.contains(','))
+        .and_then(|spec| spec.split_once('-'))
+    else {
+        return Ok(None);
+    };
+
+    match (start.trim(), end.trim()) {
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => Err(416),
+            Ok(_) if size == 0 => Err(416),
+            Ok(length) => Ok(Some(size.saturating_sub(length)..size)),
+            Err(_) => Ok(None),
+        },
+        (start, end) => {
+            let Ok(start) = start.parse::<u64>() else {
+                return Ok(None);
+            };
+            let end = match end {
+                "" => u64::MAX,
+                end => match end.parse::<u64>() {
+                    Ok(end) if end >= start => end,
+                    _ => return Ok(None),
+                },
+            };
+            if start >= size {
+                return Err(416);
+            }
+            Ok(Some(start..end.saturating_add(1).min(size)))
+        }
+    }
+}
+
+/// Reads a line of at most `limit` bytes without its line ending, or `None`
+/// when it is longer. The end of the stream reads as an empty line.
+async fn read_line<R>(reader: &mut R, limit: usize) -> io::Result<Option<String>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = Vec::new();
+    (&mut *reader)
+        .take(limit as u64 + 2)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if line.len() > limit && !line.ends_with(b"\n") {
+        return Ok(None);
+    }
+    while matches!(line.last(), Some(b'\n' | b'\r')) {
+        line.pop();
+    }
+    Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+}
+
+/// Writes one chunk of a `Transfer-Encoding: chunked` body, or the raw bytes
+/// for HTTP/1.0 clients.
+async fn write_chunk<S>(stream: &mut S, chunk: &[u8], chunked: bool) -> io::Result<()>
+where
+    S: AsyncWriteExt + Unpin,
+{
+    if chunk.is_empty() {
+        return Ok(());
+    }
+    if chunked {
+        stream
+            .write_all(format!("{:x}\r\n", chunk.len()).as_bytes())
+            .await?;
+        stream.write_all(chunk).await?;
+        stream.write_all(b"\r\n").await
+    } else {
+        stream.write_all(chunk).await
+    }
+}
+
+pub async fn handle_connection<S>(mut stream: S) -> Result<(i32, String)>
 where
     S: AsyncReadExt + AsyncWriteExt + Unpin,
 {
     let config = CONFIG.load();
     let cache_config = config.server.cache.clone().unwrap_or_default();
+    let site_rules = SITE_RULES.load();
 
     let mut response: Response = Response {
         version: "1.1",
@@ -112,130 +393,498 @@ where
 
     response.send_header("Date", Utc::now().format(DATE_FORMAT));
 
-    let buf_reader = BufReader::new(&mut stream);
-    let req = buf_reader.lines().next_line().await?.unwrap_or_default();
+    let request_id = format!(
+        "{:x}-{:x}",
+        Utc::now().timestamp(),
+        REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    response.send_header("X-Request-Id", request_id.clone());
+
+    let mut reader = BufReader::new(&mut stream);
+    let (req, too_long) = match read_line(&mut reader, MAX_REQUEST_LINE).await? {
+        Some(req) => (req, false),
+        None => (String::new(), true),
+    };
+
+    let mut headers: HashMap<String, String> = HashMap::new();
+    let mut header_lines = 0;
+    let headers_too_large = !too_long
+        && loop {
+            match read_line(&mut reader, MAX_HEADER_LINE).await? {
+                Some(line) if line.is_empty() => break false,
+                Some(_) if header_lines == MAX_HEADERS => break true,
+                Some(line) => {
+                    header_lines += 1;
+                    if let Some((k, v)) = line.split_once(':') {
+                        headers.insert(k.trim().to_ascii_lowercase(), v.trim().to_owned());
+                    }
+                }
+                None => break true,
+            }
+        };
 
     // GET /location HTTP/1.1
     let parts: Vec<&str> = req.split('/').collect();
+    let method = parts.first().unwrap().trim();
+    // HEAD is answered like GET, leaving out the body
+    let head = method == "HEAD";
+    let get = method == "GET" || head;
 
     let mut mime_type: Mime = mime::TEXT_HTML_UTF_8;
     let mut buffer: Vec<u8> = Vec::new();
+    let mut location = String::new();
+    let mut streamed: Option<Streamed> = None;
+    // a `_redirects` rewrite may serve the file it finds with its own status
+    let mut rewrite_status: Option<i32> = None;
 
-    if parts.len() < 3 {
+    let site_action = match req.split_whitespace().nth(1).filter(|_| get) {
+        Some(target) => site_rules.action(target, location_exists).await,
+        None => None,
+    };
+    // the settings of the requested location, looked up once
+    let request_config = location_config(&request_location(&req)).unwrap_or_default();
+
+    if too_long {
+        response.status_code = 414;
+    } else if headers_too_large {
+        response.status_code = 431;
+    } else if parts.len() < 3 {
         response.status_code = 400;
-    } else if parts.first().unwrap().trim() != "GET" {
-        response.status_code = 501;
-    } else if let Some(location) = &req.split_whitespace().nth(1) {
-        let location: String = urlencoding::decode(root_relative(location))
+    } else if cfg!(feature = "webdav") && !get && request_config.webdav.is_some() {
+        #[cfg(feature = "webdav")]
+        {
+            location = request_location(&req);
+            response.version = parts.last().unwrap();
+
+            match webdav(method, &location, &request_config, &headers, &mut reader).await {
+                Ok(dav) => {
+                    response.status_code = dav.status_code;
+                    for (k, v) in dav.headers {
+                        response.send_header(k, v);
+                    }
+                    if let Some(body) = dav.body {
+                        mime_type = "application/xml; charset=utf-8".parse().unwrap();
+                        buffer = body.into_bytes();
+                    }
+                }
+                Err(status_code) => {
+                    if status_code == 401 {
+                        response.send_header("WWW-Authenticate", "Basic realm=\"zest\"");
+                    }
+                    response.status_code = status_code;
+                }
+            }
+        }
+    } else if cfg!(feature = "upload") && matches!(method, "PUT" | "POST") {
+        #[cfg(feature = "upload")]
+        {
+            location = request_location(&req);
+            response.version = parts.last().unwrap();
+
+            match upload(method, &location, &request_config, &headers, &mut reader).await {
+                Ok((status_code, href)) => {
+                    response.status_code = status_code;
+                    response.send_header("Location", href);
+                }
+                Err(status_code) => {
+                    match status_code {
+                        401 => response.send_header("WWW-Authenticate", "Basic realm=\"zest\""),
+                        405 => response.send_header("Allow", "GET, HEAD"),
+                        _ => None,
+                    };
+                    response.status_code = status_code;
+                }
+            }
+        }
+    } else if !get {
+        response.status_code = match method {
+            "POST" | "PUT" | "DELETE" | "OPTIONS" | "PATCH" => {
+                response.send_header("Allow", "GET, HEAD");
+                405
+            }
+            _ => 501,
+        };
+    } else if let Some(SiteAction::Redirect(status_code, to)) = &site_action {
+        response.version = parts.last().unwrap();
+        response.status_code = *status_code;
+        response.send_header("Location", to);
+    } else if let Some(target) = &req.split_whitespace().nth(1) {
+        let (target_path, query) = target.split_once('?').unwrap_or((target, ""));
+        location = urlencoding::decode(root_relative(target_path))
             .unwrap_or_default()
             .into();
 
         response.version = parts.last().unwrap();
-        let mut path = config.server.root.join(location.split('?').next().unwrap());
-
-        path = match path.canonicalize() {
-            Ok(canonical_path) => canonical_path,
-            Err(_) => {
-                response.status_code = 404;
-                config
-                    .server
-                    .root
-                    .join(Path::new(
-                        &config
-                            .server
-                            .error_page
-                            .clone()
-                            .unwrap_or("404.html".into()),
-                    ))
-                    .to_path_buf()
-                    .canonicalize()
-                    .unwrap_or_default()
+        let resolved = match (&site_action, &request_config) {
+            (Some(SiteAction::Rewrite(status_code, to)), _) => {
+                rewrite_status = Some(*status_code);
+                Ok(to.clone())
             }
+            (_, l)
+                if l.index_hide_deny.unwrap_or(false)
+                    && l.index_hide
+                        .as_ref()
+                        .is_some_and(|hide| hide.is_hidden(&location)) =>
+            {
+                Err(404)
+            }
+            (
+                _,
+                LocationConfig {
+                    try_files: Some(candidates),
+                    ..
+                },
+            ) => try_files(candidates, &location, resolve_root).map(|(resolved, _)| resolved),
+            _ => Ok(location.clone()),
         };
-        if path.is_dir() {
-            #[allow(unused_assignments)]
-            let mut html: String = String::new();
-            #[cfg(feature = "lru_cache")]
+        let rewritten = resolved
+            .as_ref()
+            .is_ok_and(|resolved| *resolved != location);
+
+        // an index file is served whatever listing format is asked for
+        let index = rewritten || target_path.ends_with('/');
+
+        let resolved = match resolved {
+            Ok(resolved) if !rewritten && request_config.clean_urls.unwrap_or(false) => {
+                locate_clean(&resolved, index, request_config.clone()).await
+            }
+            Ok(resolved) if !rewritten => locate(&resolved, index, request_config.clone()).await,
+            Ok(resolved) => {
+                let location_config = location_config(&resolved).unwrap_or_default();
+                locate(&resolved, index, location_config).await
+            }
+            Err(status_code) => Err(status_code),
+        };
+
+        match resolved {
+            Err(status_code) => {
+                response.status_code = status_code;
+            }
+            Ok(found) if found.clean && target_path.ends_with('/') => {
+                // GET /about/ -> 301 Location: /about
+                response.status_code = 301;
+                response.send_header(
+                    "Location",
+                    if query.is_empty() {
+                        target_path.trim_end_matches('/').to_owned()
+                    } else {
+                        format!("{}?{}", target_path.trim_end_matches('/'), query)
+                    },
+                );
+            }
+            Ok(found)
+                if !rewritten
+                    && !found.meta.dir
+                    && request_config.clean_urls.unwrap_or(false)
+                    && request_config.clean_urls_redirect.unwrap_or(false)
+                    && target_path.strip_suffix(".html").is_some_and(|stem| {
+                        let name = stem.rsplit('/').next().unwrap_or_default();
+                        !name.is_empty()
+                            && !index_files(&found.location_config)
+                                .contains(&format!("{}.html", name))
+                    }) =>
             {
-                let mut cache = INDEX_CACHE.write().await;
-                if let Some(ctx) = cache.get(&location) {
-                    html.clone_from(ctx);
-                } else if let Ok(index) = location_index(path, &location).await {
-                    cache
-                        .push(location.clone(), index)
-                        .to_owned()
-                        .unwrap_or_default();
-
-                    html.clone_from(cache.get(&location).unwrap());
-                } else {
-                    response.status_code = 301;
-                }
+                // GET /about.html -> 301 Location: /about
+                let stem = target_path.trim_end_matches(".html");
+                response.status_code = 301;
+                response.send_header(
+                    "Location",
+                    if query.is_empty() {
+                        stem.to_owned()
+                    } else {
+                        format!("{}?{}", stem, query)
+                    },
+                );
+            }
+            Ok(found) if found.meta.dir && !rewritten && !target_path.ends_with('/') => {
+                // GET /dir -> 301 Location: /dir/
+                response.status_code = 301;
+                response.send_header(
+                    "Location",
+                    if query.is_empty() {
+                        format!("{}/", target_path)
+                    } else {
+                        format!("{}/?{}", target_path, query)
+                    },
+                );
             }
-            #[cfg(not(feature = "lru_cache"))]
+            #[cfg(feature = "archive")]
+            Ok(found)
+                if !found.meta.dir
+                    && target_path.ends_with('/')
+                    && query_params(query).contains_key("archive") =>
             {
-                if let Ok(index) = location_index(path, &location).await {
-                    html = index;
-                } else {
-                    response.status_code = 301;
+                // directories with an index file are pages, not downloads
+                location = found.location;
+                response.status_code = 403;
+            }
+            #[cfg(feature = "archive")]
+            Ok(found) if found.meta.dir && query_params(query).contains_key("archive") => {
+                location = found.location;
+                let params = query_params(query);
+                let enabled = found.location_config.archive.unwrap_or(false);
+
+                match ArchiveFormat::new(&params["archive"]) {
+                    None => response.status_code = 400,
+                    Some(_) if !enabled || !found.location_config.auto_index.unwrap_or(true) => {
+                        response.status_code = 403
+                    }
+                    Some(format) => match archive_entries(&location).await {
+                        Ok(entries) => {
+                            mime_type = format.mime();
+                            response.send_header(
+                                "Content-Disposition",
+                                archive_disposition(&location, format),
+                            );
+                            streamed = Some(Streamed::Archive(archive_stream(format, entries)));
+                        }
+                        Err(status_code) => response.status_code = status_code,
+                    },
                 }
             }
+            Ok(found) if found.meta.dir => {
+                location = found.location.clone();
+                let options = IndexOptions::new(
+                    &query_params(query),
+                    headers.get("accept").map(|s| s.as_str()),
+                );
+                mime_type = options.format.mime();
+                response.send_header("Vary", "Accept");
 
-            buffer = html.into_bytes();
-        } else {
-            // path.is_file()
-            match File::open(path.clone()).await {
-                Ok(f) => {
-                    let mut file = f;
-                    mime_type = mime_match(path.to_str().unwrap());
+                let key = options.cache_key(&found.meta.layer, &location);
+                #[cfg(feature = "lru_cache")]
+                if crate::config::DIR_CONFIGS_CHANGED.swap(false, Ordering::Relaxed) {
+                    INDEX_CACHE.write().await.clear();
+                }
+                #[cfg(feature = "lru_cache")]
+                let cached = INDEX_CACHE.write().await.get(&key).cloned();
+                #[cfg(not(feature = "lru_cache"))]
+                let cached: Option<String> = None;
 
-                    #[cfg(feature = "lru_cache")]
+                if let Some(html) = cached {
+                    buffer = html.into_bytes();
+                } else {
+                    match location_index(
+                        found.storage.clone(),
+                        &found.path,
+                        &location,
+                        &found.location_config,
+                        options,
+                    )
+                    .await
                     {
-                        let mut cache = FILE_CACHE.write().await;
-                        if let Some(content) = cache.get(&location) {
-                            buffer = content.to_vec();
-                        } else {
-                            file.read_to_end(&mut buffer).await?;
-                            if file.metadata().await.unwrap().len()
-                                < cache_config
-                                    .file_maxsize
-                                    .unwrap_or(32768 * 1024 /* 32 MB */)
-                            {
-                                cache
-                                    .push(location.clone(), buffer.clone())
-                                    .to_owned()
-                                    .unwrap_or_default();
-                            }
+                        Ok(Index::Listing(listing))
+                            if listing.total().is_none_or(|total| {
+                                total > cache_config.index_maxentries.unwrap_or(10000)
+                            }) =>
+                        {
+                            streamed = Some(Streamed::Listing(listing));
                         }
+                        Ok(index) => {
+                            let html = index.render().await;
+                            #[cfg(feature = "lru_cache")]
+                            INDEX_CACHE.write().await.put(key, html.clone());
+                            #[cfg(not(feature = "lru_cache"))]
+                            let _ = key;
+
+                            buffer = html.into_bytes();
+                        }
+                        Err(e) => response.status_code = index_error(e),
                     }
+                }
+            }
+            Ok(found) => {
+                location = found.location;
+                mime_type = found
+                    .meta
+                    .mime
+                    .clone()
+                    .unwrap_or_else(|| mime_match(&found.path));
+
+                let etag = found.meta.etag();
+                response.send_header("ETag", etag.clone());
+                response.send_header("Accept-Ranges", "bytes");
+                response.send_header(
+                    "Last-Modified",
+                    DateTime::from_timestamp(found.meta.mtime, 0)
+                        .unwrap_or_default()
+                        .format(DATE_FORMAT),
+                );
 
+                // the gzip variant is validated by its own tag, and without
+                // reading it when the client already has it
+                let accepts_gzip = headers.get("accept-encoding").is_some_and(|encodings| {
+                    encodings
+                        .split(',')
+                        .any(|encoding| encoding.split(';').next().unwrap().trim() == "gzip")
+                });
+                let gzip_etag = gzip_etag(&etag);
+                let matched = headers.get("if-none-match").and_then(|tags| {
+                    tags.split(',')
+                        .map(|tag| tag.trim().trim_start_matches("W/"))
+                        .find(|tag| {
+                            *tag == "*" || *tag == etag || (accepts_gzip && *tag == gzip_etag)
+                        })
+                        .map(|tag| tag == gzip_etag)
+                });
+                let gzip = match matched {
+                    Some(_) => None,
+                    None => match found.storage.read_gzip(&found.path).await {
+                        Ok(Some(gzip)) => {
+                            response.send_header("Vary", "Accept-Encoding");
+                            accepts_gzip.then_some(gzip)
+                        }
+                        _ => None,
+                    },
+                };
+
+                // a range is served from the file itself, and only while it
+                // is still the version `If-Range` names
+                let size = found.meta.size;
+                let range = headers
+                    .get("range")
+                    .filter(|_| headers.get("if-range").is_none_or(|tag| tag.trim() == etag))
+                    .and_then(|range| byte_range(range, size).transpose());
+
+                if let Some(gzipped) = matched {
+                    response.status_code = 304;
+                    if gzipped {
+                        response.send_header("ETag", gzip_etag);
+                        response.send_header("Vary", "Accept-Encoding");
+                    }
+                } else if let Some(Err(status_code)) = range {
+                    response.status_code = status_code;
+                    response.send_header("Content-Range", format!("bytes */{}", size));
+                } else if let Some(Ok(range)) = range {
+                    match found.storage.read_range(&found.path, range.clone()).await {
+                        Ok(content) => {
+                            response.status_code = 206;
+                            response.send_header(
+                                "Content-Range",
+                                format!("bytes {}-{}/{}", range.start, range.end - 1, size),
+                            );
+                            buffer = content;
+                        }
+                        Err(e) => response.status_code = error_status(&e),
+                    }
+                } else if let Some(gzip) = gzip {
+                    response.send_header("ETag", gzip_etag);
+                    response.send_header("Content-Encoding", "gzip");
+                    buffer = gzip;
+                } else {
+                    // files too large to cache skip the cache altogether
+                    let key = format!("{}:{}", found.meta.layer, location);
+                    let cacheable = cfg!(feature = "lru_cache")
+                        && found.meta.size
+                            <= cache_config
+                                .file_maxsize
+                                .or(CacheConfig::default().file_maxsize)
+                                .unwrap()
+                                .0;
+                    #[cfg(feature = "lru_cache")]
+                    let cached = if cacheable {
+                        FILE_CACHE.write().await.get(&key).cloned()
+                    } else {
+                        None
+                    };
                     #[cfg(not(feature = "lru_cache"))]
-                    file.read_to_end(&mut buffer).await?;
-
-                    response.send_header(
-                        "Last-Modified",
-                        DateTime::from_timestamp(file.metadata().await?.st_atime(), 0)
-                            .unwrap()
-                            .format(DATE_FORMAT),
-                    );
-                }
-                Err(_) => {
-                    response.status_code = 500;
+                    let cached: Option<Vec<u8>> = None;
+
+                    match cached {
+                        Some(content) => buffer = content,
+                        None => match read(found.storage.as_ref(), &found.path).await {
+                            Ok(content) => {
+                                #[cfg(feature = "lru_cache")]
+                                if cacheable {
+                                    FILE_CACHE.write().await.put(key, content.clone());
+                                }
+                                #[cfg(not(feature = "lru_cache"))]
+                                let _ = (key, cacheable);
+                                buffer = content;
+                            }
+                            Err(e) => response.status_code = error_status(&e),
+                        },
+                    }
                 }
-            };
+            }
+        }
+        if response.status_code != 200 {
+            rewrite_status = None;
+        } else if let Some(status_code) = rewrite_status {
+            response.status_code = status_code;
         }
     } else {
         response.status_code = 400;
     }
 
-    if response.status_code != 200 {
-        buffer = status_page(&response.status(response.status_code), server_info)
-            .await
-            .into()
+    if matches!(response.status_code, 204 | 304) {
+        buffer.clear();
+    } else if !(200..300).contains(&response.status_code) && rewrite_status.is_none() {
+        mime_type = mime::TEXT_HTML_UTF_8;
+        let status = response.status(response.status_code);
+        let (status_code, reason) = status.split_once(' ').unwrap();
+        let path = format!("/{}", location);
+        buffer = match error_page(
+            response.status_code,
+            &request_config,
+            &[
+                ("status", status_code),
+                ("reason", reason),
+                ("path", &path),
+                ("request_id", &request_id),
+                ("server", &server_info),
+            ],
+        )
+        .await
+        {
+            Some(page) => page,
+            None => status_page(response.status_code, &status, server_info).await,
+        }
+        .into()
+    }
+    // `_headers` rules match the path that was served, `..` and all resolved
+    if let Ok(path) = normalize_location(&request_location(&req)) {
+        for (name, value) in site_rules.headers(&format!("/{}", path)) {
+            response.send_header(name, value);
+        }
     }
-    response.send_header("Content-Length", buffer.len());
     response.send_header("Content-Type", mime_type);
-    stream.write_all(response.resp().as_bytes()).await?;
-    stream.write_all(&buffer).await?;
+    match streamed.filter(|_| response.status_code == 200 || rewrite_status.is_some()) {
+        Some(body) => {
+            let chunked = response.version != "1.0";
+            if chunked {
+                response.send_header("Transfer-Encoding", "chunked");
+            }
+            stream.write_all(response.resp().as_bytes()).await?;
+            match body {
+                _ if head => {}
+                Streamed::Listing(listing) => {
+                    let mut chunks = listing.into_chunks();
+                    while let Some(chunk) = chunks.next().await {
+                        write_chunk(&mut stream, chunk.as_bytes(), chunked).await?;
+                    }
+                }
+                #[cfg(feature = "archive")]
+                Streamed::Archive(mut rx) => {
+                    // an error mid-archive drops the connection without the
+                    // terminating chunk, so the client sees it as truncated
+                    while let Some(chunk) = rx.recv().await {
+                        write_chunk(&mut stream, &chunk?, chunked).await?;
+                    }
+                }
+            }
+            if chunked && !head {
+                stream.write_all(b"0\r\n\r\n").await?;
+            }
+        }
+        None => {
+            response.send_header("Content-Length", buffer.len());
+            stream.write_all(response.resp().as_bytes()).await?;
+            if !head {
+                stream.write_all(&buffer).await?;
+            }
+        }
+    }
     stream.flush().await?;
     stream.shutdown().await?;
 
@@ -365,6 +1014,8 @@ pub async fn zest_main() -> Result<(), Box<dyn Error>> {
     #[cfg(feature = "lru_cache")]
     init_cache().await.context("failed to init lru cache")?;
 
+    load_site_rules();
+
     loop {
         let (tx, rx): (Sender<()>, Receiver<()>) = oneshot::channel();
         let config = CONFIG.load();
@@ -386,7 +1037,15 @@ async fn signal_handler(tx: Sender<()>) -> io::Result<()> {
     tokio::spawn(async move {
         for sig in signals.forever() {
             if sig == SIGHUP {
-                let config: crate::config::Config = init_config();
+                // a config that fails to load leaves the running one in place
+                let config = match load_config() {
+                    Ok(config) => config,
+                    Err(_e) => {
+                        #[cfg(feature = "log")]
+                        error!("{:#}", _e);
+                        continue;
+                    }
+                };
 
                 CONFIG.store(Arc::new(config.clone()));
 
@@ -400,19 +1059,25 @@ async fn signal_handler(tx: Sender<()>) -> io::Result<()> {
                     }
                 }
 
-                let cache = config.server.cache.unwrap_or_default();
-                let (index_capacity, file_capacity) =
-                    (cache.index_capacity.unwrap(), cache.file_capacity.unwrap());
+                #[cfg(feature = "lru_cache")]
+                {
+                    let cache = config.server.cache.clone().unwrap_or_default();
+                    INDEX_CACHE.write().await.resize(index_budget(&cache));
+                    INDEX_CACHE.write().await.clear();
+                    FILE_CACHE.write().await.resize(file_budget(&cache));
+                    CLEAN_URL_CACHE.write().await.clear();
+                }
 
-                INDEX_CACHE
-                    .write()
-                    .await
-                    .resize(NonZero::new(index_capacity).unwrap());
+                #[cfg(feature = "index_template")]
+                {
+                    *TEMPLATES.write().await = init_templates();
+                }
 
-                FILE_CACHE
-                    .write()
-                    .await
-                    .resize(NonZero::new(file_capacity).unwrap());
+                #[cfg(feature = "archive")]
+                MOUNTED_ARCHIVES.lock().unwrap().clear();
+                DIR_CONFIGS.lock().unwrap().clear();
+                PASSWD_HOMES.lock().unwrap().take();
+                load_site_rules();
 
                 tx.send(()).unwrap();
                 return;
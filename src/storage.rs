@@ -0,0 +1,748 @@
+use crate::{
+    config::{SymlinkPolicy, CONFIG, DIR_CONFIG, START_DIR},
+    route::{location_match, normalize_location, resolve_path},
+    rules::{HEADERS, REDIRECTS},
+};
+use futures::{
+    future::{self, BoxFuture},
+    stream::{self, BoxStream},
+    FutureExt, Stream, StreamExt,
+};
+use lazy_static::lazy_static;
+use mime::Mime;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{self, Cursor, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::SystemTime,
+};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt},
+};
+
+#[cfg(target_os = "android")]
+use std::os::android::fs::MetadataExt;
+
+#[cfg(target_os = "linux")]
+use std::os::linux::fs::MetadataExt;
+
+/// Entries whose metadata is fetched concurrently while listing a directory.
+const INDEX_CONCURRENCY: usize = 64;
+
+/// Suffix of the temporary files uploads are written to, renamed into place
+/// once complete.
+pub const PARTIAL_UPLOAD: &str = ".upload";
+
+/// What a storage knows about a file or directory.
+#[derive(Clone, Debug, Default)]
+pub struct Metadata {
+    pub dir: bool,
+    pub size: u64,
+    pub mtime: i64,
+    /// Where a symlink points, for storages that have them.
+    pub symlink: Option<String>,
+    /// A validator better than mtime and size, if the storage has one.
+    pub etag: Option<String>,
+    /// The content type, if the storage knows better than the file name.
+    pub mime: Option<Mime>,
+    /// Which layers of overlays the path was found in, as dotted indices.
+    pub layer: String,
+}
+
+impl Metadata {
+    #[inline]
+    pub fn etag(&self) -> String {
+        self.etag
+            .clone()
+            .unwrap_or_else(|| format!("\"{:x}-{:x}\"", self.mtime, self.size))
+    }
+}
+
+pub struct StorageEntry {
+    pub name: String,
+    pub meta: Metadata,
+}
+
+pub type StorageReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// A tree of files to serve and list.
+///
+/// Paths are relative to the tree's root, `/`-separated and already
+/// normalized, with the empty path naming the root itself.
+pub trait Storage: Send + Sync {
+    fn open<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<StorageReader>>;
+
+    /// Describes `path`, following symlinks.
+    fn stat<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<Metadata>>;
+
+    /// Lists the direct children of the directory at `path`, in no order.
+    fn read_dir<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<Vec<StorageEntry>>>;
+
+    /// Streams the direct children of the directory at `path` as they are
+    /// read, for storages that can list without holding them all.
+    fn entries(
+        self: Arc<Self>,
+        path: String,
+    ) -> BoxFuture<'static, io::Result<BoxStream<'static, StorageEntry>>>
+    where
+        Self: 'static,
+    {
+        async move { Ok(stream::iter(self.read_dir(&path).await?).boxed()) }.boxed()
+    }
+
+    fn read_range<'a>(
+        &'a self,
+        path: &'a str,
+        range: Range<u64>,
+    ) -> BoxFuture<'a, io::Result<Vec<u8>>>;
+
+    /// The file gzipped, for storages that keep it compressed.
+    fn read_gzip<'a>(&'a self, _path: &'a str) -> BoxFuture<'a, io::Result<Option<Vec<u8>>>> {
+        async { Ok(None) }.boxed()
+    }
+}
+
+/// Reads the whole file at `path`.
+pub async fn read(storage: &dyn Storage, path: &str) -> io::Result<Vec<u8>> {
+    let mut content = vec![];
+    storage.open(path).await?.read_to_end(&mut content).await?;
+    Ok(content)
+}
+
+/// Whether `name` is an upload still being written, `.name.pid-n.upload`,
+/// which is neither served nor listed.
+pub fn is_partial_upload(name: &str) -> bool {
+    let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    name.starts_with('.')
+        && name
+            .strip_suffix(PARTIAL_UPLOAD)
+            .and_then(|rest| rest.rsplit_once('.'))
+            .and_then(|(_, id)| id.split_once('-'))
+            .is_some_and(|(pid, n)| digits(pid) && digits(n))
+}
+
+/// Whether `location` is one of the site rule files at the root,
+/// `_redirects` or `_headers`, which are neither served nor listed.
+pub fn is_site_rules(location: &str) -> bool {
+    matches!(location.trim_matches('/'), REDIRECTS | HEADERS)
+}
+
+/// The status code answering a failed storage operation.
+pub fn error_status(e: &io::Error) -> i32 {
+    match e.kind() {
+        io::ErrorKind::NotFound => 404,
+        io::ErrorKind::PermissionDenied => 403,
+        io::ErrorKind::InvalidInput => 400,
+        _ => 500,
+    }
+}
+
+/// The storage error standing for a status code.
+pub fn status_error(status_code: i32) -> io::Error {
+    io::Error::from(match status_code {
+        404 => io::ErrorKind::NotFound,
+        403 => io::ErrorKind::PermissionDenied,
+        400 => io::ErrorKind::InvalidInput,
+        _ => io::ErrorKind::Other,
+    })
+}
+
+/// Picks the storage serving `location` and the path within it.
+///
+/// This is the server root unless the location maps to another directory
+/// through `alias` or `root`, mounts an archive through `archive_root` or
+/// layers the embedded tree beneath it through `embed`.
+pub async fn location_storage(location: &str) -> Result<(Arc<dyn Storage>, String), i32> {
+    let config = CONFIG.load();
+    let location = normalize_location(location)?;
+    let deny_hidden = config.server.deny_hidden.unwrap_or(false);
+    if is_site_rules(&location)
+        || location.split('/').any(|part| {
+            part == DIR_CONFIG || is_partial_upload(part) || (deny_hidden && part.starts_with('.'))
+        })
+    {
+        return Err(404);
+    }
+
+    let (local, local_path) = match location_alias(&location)? {
+        Some((dir, path)) => (Arc::new(LocalStorage::new(&dir)) as Arc<dyn Storage>, path),
+        None => (root_storage(), location.clone()),
+    };
+
+    #[cfg(any(feature = "archive", feature = "embed"))]
+    if let Some(matched) = location_match(&location) {
+        let (prefix, location_config) = (&matched.prefix, &matched.config);
+        let rest = location
+            .strip_prefix(prefix.as_str())
+            .filter(|rest| prefix.is_empty() || rest.is_empty() || rest.starts_with('/'))
+            .ok_or(404)?
+            .trim_start_matches('/')
+            .to_owned();
+
+        #[cfg(feature = "archive")]
+        if let Some(archive_root) = &location_config.archive_root {
+            let archive = crate::archive::mounted_archive(archive_root).await?;
+            return Ok((archive, rest));
+        }
+
+        #[cfg(feature = "embed")]
+        if let Some(embed) = &location_config.embed {
+            let embed = normalize_location(embed.to_str().ok_or(404)?)?;
+            let base = match location_config.alias {
+                Some(_) => "",
+                None => prefix,
+            };
+            let overlay = OverlayStorage::new(vec![
+                Arc::new(SubtreeStorage::new(local, base)),
+                Arc::new(SubtreeStorage::new(
+                    Arc::new(crate::embed::EmbeddedStorage),
+                    &embed,
+                )),
+            ]);
+            return Ok((Arc::new(overlay), rest));
+        }
+    }
+
+    Ok((local, local_path))
+}
+
+/// Whether `location` would be served from the storage picked for it, be it
+/// a directory, an aliased or embedded file or an archive member.
+pub async fn location_exists(location: &str) -> bool {
+    match location_storage(location).await {
+        Ok((storage, path)) => storage.stat(&path).await.is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// The directory a location's `alias` or `root` maps `location` into, with
+/// the path there. `alias` replaces the matched prefix while `root` keeps it.
+/// `~user` locations map into the user's directory when `userdir` is set.
+pub fn location_alias(location: &str) -> Result<Option<(PathBuf, String)>, i32> {
+    let location = normalize_location(location)?;
+    if let Some(user_dir) = user_dir(&location) {
+        return user_dir.map(Some);
+    }
+    let Some(matched) = location_match(&location) else {
+        return Ok(None);
+    };
+    if let Some(alias) = &matched.config.alias {
+        let rest = location
+            .strip_prefix(matched.prefix.as_str())
+            .ok_or(404)?
+            .trim_start_matches('/');
+        Ok(Some((START_DIR.join(alias), rest.to_owned())))
+    } else {
+        Ok(matched
+            .config
+            .root
+            .as_ref()
+            .map(|root| (START_DIR.join(root), location)))
+    }
+}
+
+/// The directory a `~user` location maps into and the path there, if
+/// `userdir` is set. Users missing from `/etc/passwd` or not allowed are 404.
+pub fn user_dir(location: &str) -> Option<Result<(PathBuf, String), i32>> {
+    let config = CONFIG.load();
+    let userdir = config.server.userdir.as_ref()?;
+    let (user, rest) = location.split_once('/').unwrap_or((location, ""));
+    let user = user.strip_prefix('~')?;
+
+    let allowed = userdir
+        .allow
+        .as_ref()
+        .is_none_or(|allow| allow.iter().any(|name| name == user))
+        && !match &userdir.deny {
+            Some(deny) => deny.iter().any(|name| name == user),
+            None => user == "root",
+        };
+    let home = passwd_homes()
+        .get(user)
+        .cloned()
+        .filter(|_| allowed && !user.is_empty());
+    Some(match home {
+        Some(home) => Ok((
+            home.join(userdir.dir.as_deref().unwrap_or(Path::new("public_html"))),
+            rest.to_owned(),
+        )),
+        None => Err(404),
+    })
+}
+
+const PASSWD: &str = "/etc/passwd";
+
+lazy_static! {
+    /// The home directories of `/etc/passwd` with the mtime it was read at,
+    /// dropped on SIGHUP.
+    pub static ref PASSWD_HOMES: Mutex<Option<CachedPasswd>> = Mutex::new(None);
+}
+
+pub type CachedPasswd = (SystemTime, Arc<HashMap<String, PathBuf>>);
+
+/// The home directory of each user in `/etc/passwd`, reread when it changes.
+fn passwd_homes() -> Arc<HashMap<String, PathBuf>> {
+    let Ok(mtime) = std::fs::metadata(PASSWD).and_then(|meta| meta.modified()) else {
+        return Arc::default();
+    };
+    let mut cached = PASSWD_HOMES.lock().unwrap();
+    match &*cached {
+        Some((read, homes)) if *read == mtime => Arc::clone(homes),
+        _ => {
+            let homes = Arc::new(parse_passwd(
+                &std::fs::read_to_string(PASSWD).unwrap_or_default(),
+            ));
+            *cached = Some((mtime, Arc::clone(&homes)));
+            homes
+        }
+    }
+}
+
+/// The home directories in the `name:password:uid:gid:gecos:home:shell`
+/// lines of a passwd file, the first line of a user winning.
+pub fn parse_passwd(passwd: &str) -> HashMap<String, PathBuf> {
+    let mut homes = HashMap::new();
+    for line in passwd.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() == 7 && !fields[5].is_empty() {
+            homes
+                .entry(fields[0].to_owned())
+                .or_insert_with(|| PathBuf::from(fields[5]));
+        }
+    }
+    homes
+}
+
+/// Where uploads and WebDAV write `location`: the directory its location
+/// maps to, or else the first root layer.
+pub fn location_root(location: &str) -> Result<(PathBuf, String), i32> {
+    Ok(location_alias(location)?
+        .unwrap_or_else(|| (CONFIG.load().server.root.to_path_buf(), location.to_owned())))
+}
+
+/// The server root, overlaying its layers when it has several.
+pub fn root_storage() -> Arc<dyn Storage> {
+    let config = CONFIG.load();
+    match config.server.root.layers() {
+        [root] => Arc::new(LocalStorage::new(root)),
+        layers => Arc::new(OverlayStorage::new(
+            layers
+                .iter()
+                .map(|root| Arc::new(LocalStorage::new(root)) as Arc<dyn Storage>)
+                .collect(),
+        )),
+    }
+}
+
+/// Resolves `location` like `resolve_path` in the directory its location
+/// maps to, or else in the first root layer having it. Errors other than a
+/// missing path end the search.
+pub fn resolve_root(location: &str) -> Result<PathBuf, i32> {
+    let config = CONFIG.load();
+    let symlinks = config.server.symlinks.unwrap_or_default();
+    let deny_hidden = config.server.deny_hidden.unwrap_or(false);
+    if let Some((dir, path)) = location_alias(location)? {
+        return resolve_path(&dir, &path, symlinks, deny_hidden);
+    }
+
+    for root in config.server.root.layers() {
+        match resolve_path(root, location, symlinks, deny_hidden) {
+            Err(404) => continue,
+            resolved => return resolved,
+        }
+    }
+    Err(404)
+}
+
+/// What the paths below the directory `path` start with.
+#[inline]
+pub fn dir_prefix(path: &str) -> String {
+    if path.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", path)
+    }
+}
+
+/// Files below a directory, kept inside it like requests to the server root.
+pub struct LocalStorage {
+    root: PathBuf,
+    symlinks: SymlinkPolicy,
+    deny_hidden: bool,
+}
+
+impl LocalStorage {
+    /// Serves `root` with the server's symlink and dotfile policies.
+    pub fn new(root: &Path) -> Self {
+        let config = CONFIG.load();
+        LocalStorage {
+            root: root.to_owned(),
+            symlinks: config.server.symlinks.unwrap_or_default(),
+            deny_hidden: config.server.deny_hidden.unwrap_or(false),
+        }
+    }
+
+    #[inline]
+    pub fn resolve(&self, path: &str) -> io::Result<PathBuf> {
+        resolve_path(&self.root, path, self.symlinks, self.deny_hidden).map_err(status_error)
+    }
+}
+
+/// The entries of a directory with their metadata, fetched concurrently.
+/// Entries that vanish or cannot be described are left out rather than
+/// failing the whole listing.
+fn local_entries(entries: fs::ReadDir) -> impl Stream<Item = StorageEntry> + Send + 'static {
+    stream::unfold(entries, |mut entries| async move {
+        let entry = entries.next_entry().await.ok()??;
+        Some((entry, entries))
+    })
+    .map(|entry| async move {
+        Some(StorageEntry {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            meta: local_metadata(&entry.path()).await.ok()?,
+        })
+    })
+    .buffer_unordered(INDEX_CONCURRENCY)
+    .filter_map(future::ready)
+}
+
+impl Storage for LocalStorage {
+    fn open<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<StorageReader>> {
+        async move {
+            let file = File::open(self.resolve(path)?).await?;
+            Ok(Box::new(file) as StorageReader)
+        }
+        .boxed()
+    }
+
+    fn stat<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<Metadata>> {
+        async move { local_metadata(&self.resolve(path)?).await }.boxed()
+    }
+
+    fn read_dir<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<Vec<StorageEntry>>> {
+        async move {
+            let entries = fs::read_dir(self.resolve(path)?).await?;
+            Ok(local_entries(entries).collect().await)
+        }
+        .boxed()
+    }
+
+    fn entries(
+        self: Arc<Self>,
+        path: String,
+    ) -> BoxFuture<'static, io::Result<BoxStream<'static, StorageEntry>>> {
+        async move {
+            let entries = fs::read_dir(self.resolve(&path)?).await?;
+            Ok(local_entries(entries).boxed())
+        }
+        .boxed()
+    }
+
+    fn read_range<'a>(
+        &'a self,
+        path: &'a str,
+        range: Range<u64>,
+    ) -> BoxFuture<'a, io::Result<Vec<u8>>> {
+        async move {
+            let mut file = File::open(self.resolve(path)?).await?;
+            file.seek(SeekFrom::Start(range.start)).await?;
+            let mut content = vec![];
+            file.take(range.end.saturating_sub(range.start))
+                .read_to_end(&mut content)
+                .await?;
+            Ok(content)
+        }
+        .boxed()
+    }
+}
+
+/// Describes the file, directory or symlink at `path` on disk.
+pub async fn local_metadata(path: &Path) -> io::Result<Metadata> {
+    let meta = fs::symlink_metadata(path).await?;
+    let symlink = if meta.is_symlink() {
+        Some(fs::read_link(path).await?.display().to_string())
+    } else {
+        None
+    };
+    let target = fs::metadata(path).await.unwrap_or(meta);
+
+    Ok(Metadata {
+        dir: target.is_dir(),
+        size: target.len(),
+        mtime: target.st_mtime(),
+        symlink,
+        ..Default::default()
+    })
+}
+
+/// Files held in memory by path, with directories implied by the paths.
+#[derive(Default)]
+pub struct MemoryStorage {
+    files: RwLock<BTreeMap<String, (Vec<u8>, i64)>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, path: &str, content: impl Into<Vec<u8>>, mtime: i64) {
+        if let Ok(path) = normalize_location(path) {
+            self.files
+                .write()
+                .unwrap()
+                .insert(path, (content.into(), mtime));
+        }
+    }
+
+    pub fn remove(&self, path: &str) -> Option<Vec<u8>> {
+        self.files
+            .write()
+            .unwrap()
+            .remove(path)
+            .map(|(content, _)| content)
+    }
+
+    fn content(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.files
+            .read()
+            .unwrap()
+            .get(path)
+            .map(|(content, _)| content.clone())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn open<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<StorageReader>> {
+        async move { Ok(Box::new(Cursor::new(self.content(path)?)) as StorageReader) }.boxed()
+    }
+
+    fn stat<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<Metadata>> {
+        async move {
+            let files = self.files.read().unwrap();
+            if let Some((content, mtime)) = files.get(path) {
+                return Ok(Metadata {
+                    size: content.len() as u64,
+                    mtime: *mtime,
+                    ..Default::default()
+                });
+            }
+
+            let prefix = dir_prefix(path);
+            let mtime = files
+                .range(prefix.clone()..)
+                .take_while(|(name, _)| name.starts_with(&prefix))
+                .map(|(_, (_, mtime))| *mtime)
+                .max();
+            match mtime {
+                Some(mtime) => Ok(Metadata {
+                    dir: true,
+                    mtime,
+                    ..Default::default()
+                }),
+                None if path.is_empty() => Ok(Metadata {
+                    dir: true,
+                    ..Default::default()
+                }),
+                None => Err(io::Error::from(io::ErrorKind::NotFound)),
+            }
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<Vec<StorageEntry>>> {
+        async move {
+            if !self.stat(path).await?.dir {
+                return Err(io::Error::from(io::ErrorKind::NotADirectory));
+            }
+
+            let prefix = dir_prefix(path);
+            let mut entries: Vec<StorageEntry> = vec![];
+            let mut dirs = HashSet::new();
+            let files = self.files.read().unwrap();
+            for (name, (content, mtime)) in files
+                .range(prefix.clone()..)
+                .take_while(|(name, _)| name.starts_with(&prefix))
+            {
+                match name[prefix.len()..].split_once('/') {
+                    Some((dir, _)) => {
+                        if dirs.insert(dir.to_owned()) {
+                            entries.push(StorageEntry {
+                                name: dir.to_owned(),
+                                meta: Metadata {
+                                    dir: true,
+                                    mtime: *mtime,
+                                    ..Default::default()
+                                },
+                            });
+                        }
+                    }
+                    None => entries.push(StorageEntry {
+                        name: name[prefix.len()..].to_owned(),
+                        meta: Metadata {
+                            size: content.len() as u64,
+                            mtime: *mtime,
+                            ..Default::default()
+                        },
+                    }),
+                }
+            }
+            Ok(entries)
+        }
+        .boxed()
+    }
+
+    fn read_range<'a>(
+        &'a self,
+        path: &'a str,
+        range: Range<u64>,
+    ) -> BoxFuture<'a, io::Result<Vec<u8>>> {
+        async move {
+            let content = self.content(path)?;
+            let end = (range.end as usize).min(content.len());
+            Ok(content[(range.start as usize).min(end)..end].to_vec())
+        }
+        .boxed()
+    }
+}
+
+/// Storages layered over each other: lookups take the first layer having
+/// the path and listings merge every layer, upper layers winning on names.
+pub struct OverlayStorage {
+    layers: Vec<Arc<dyn Storage>>,
+}
+
+impl OverlayStorage {
+    pub fn new(layers: Vec<Arc<dyn Storage>>) -> Self {
+        OverlayStorage { layers }
+    }
+
+    /// The first layer with something at `path`, and its index. Errors other
+    /// than a missing path stop the search, so that a denied upper file is
+    /// not bypassed.
+    async fn layer(&self, path: &str) -> io::Result<(usize, &dyn Storage)> {
+        for (i, layer) in self.layers.iter().enumerate() {
+            match layer.stat(path).await {
+                Ok(_) => return Ok((i, layer.as_ref())),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::from(io::ErrorKind::NotFound))
+    }
+}
+
+impl Storage for OverlayStorage {
+    fn open<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<StorageReader>> {
+        async move { self.layer(path).await?.1.open(path).await }.boxed()
+    }
+
+    fn stat<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<Metadata>> {
+        async move {
+            let (i, layer) = self.layer(path).await?;
+            let mut meta = layer.stat(path).await?;
+            meta.layer = if meta.layer.is_empty() {
+                i.to_string()
+            } else {
+                format!("{}.{}", i, meta.layer)
+            };
+            Ok(meta)
+        }
+        .boxed()
+    }
+
+    fn read_dir<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<Vec<StorageEntry>>> {
+        async move {
+            let mut found = false;
+            let mut names = HashSet::new();
+            let mut entries = vec![];
+            for layer in &self.layers {
+                match layer.read_dir(path).await {
+                    Ok(children) => {
+                        found = true;
+                        entries.extend(
+                            children
+                                .into_iter()
+                                .filter(|child| names.insert(child.name.clone())),
+                        );
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+
+            if found {
+                Ok(entries)
+            } else {
+                Err(io::Error::from(io::ErrorKind::NotFound))
+            }
+        }
+        .boxed()
+    }
+
+    fn read_range<'a>(
+        &'a self,
+        path: &'a str,
+        range: Range<u64>,
+    ) -> BoxFuture<'a, io::Result<Vec<u8>>> {
+        async move { self.layer(path).await?.1.read_range(path, range).await }.boxed()
+    }
+
+    fn read_gzip<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<Option<Vec<u8>>>> {
+        async move { self.layer(path).await?.1.read_gzip(path).await }.boxed()
+    }
+}
+
+/// A directory of another storage, served as a root of its own.
+pub struct SubtreeStorage {
+    inner: Arc<dyn Storage>,
+    base: String,
+}
+
+impl SubtreeStorage {
+    pub fn new(inner: Arc<dyn Storage>, base: &str) -> Self {
+        SubtreeStorage {
+            inner,
+            base: base.trim_matches('/').to_owned(),
+        }
+    }
+
+    #[inline]
+    fn path(&self, path: &str) -> String {
+        match (self.base.is_empty(), path.is_empty()) {
+            (true, _) => path.to_owned(),
+            (false, true) => self.base.clone(),
+            (false, false) => format!("{}/{}", self.base, path),
+        }
+    }
+}
+
+impl Storage for SubtreeStorage {
+    fn open<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<StorageReader>> {
+        async move { self.inner.open(&self.path(path)).await }.boxed()
+    }
+
+    fn stat<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<Metadata>> {
+        async move { self.inner.stat(&self.path(path)).await }.boxed()
+    }
+
+    fn read_dir<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<Vec<StorageEntry>>> {
+        async move { self.inner.read_dir(&self.path(path)).await }.boxed()
+    }
+
+    fn read_range<'a>(
+        &'a self,
+        path: &'a str,
+        range: Range<u64>,
+    ) -> BoxFuture<'a, io::Result<Vec<u8>>> {
+        async move { self.inner.read_range(&self.path(path), range).await }.boxed()
+    }
+
+    fn read_gzip<'a>(&'a self, path: &'a str) -> BoxFuture<'a, io::Result<Option<Vec<u8>>>> {
+        async move { self.inner.read_gzip(&self.path(path)).await }.boxed()
+    }
+}
@@ -0,0 +1,560 @@
+use crate::{
+    config::{LocationConfig, OverwritePolicy, UploadConfig, CONFIG},
+    route::{normalize_location, resolve_path, HidePatterns},
+    storage::{location_root, PARTIAL_UPLOAD},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::{
+    fs::{hard_link, remove_file, rename, File},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+};
+
+#[cfg(feature = "log")]
+use log::logger;
+
+#[cfg(feature = "lru_cache")]
+use crate::init::{CLEAN_URL_CACHE, FILE_CACHE, INDEX_CACHE};
+
+/// Bytes read from the request body at a time.
+const UPLOAD_CHUNK: usize = 64 * 1024;
+
+/// Longest header block accepted for a multipart part.
+const MAX_PART_HEADERS: usize = 16 * 1024;
+
+/// Times `commit` looks for another name when the one it picked is taken
+/// before the file is linked there.
+const COMMIT_ATTEMPTS: usize = 8;
+
+static UPLOAD_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Stores the body of a `PUT /dir/file` or the files of a multipart
+/// `POST /dir/` under the request's location, which `location_config`
+/// applies to.
+///
+/// Returns the status code with the href to send as `Location`, or the
+/// status code to answer with when the upload is refused.
+pub async fn upload<S>(
+    method: &str,
+    location: &str,
+    location_config: &LocationConfig,
+    headers: &HashMap<String, String>,
+    reader: &mut BufReader<&mut S>,
+) -> Result<(i32, String), i32>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let config = CONFIG.load();
+    if method == "PUT" && location.ends_with('/') {
+        return Err(405);
+    }
+    let location = normalize_location(location)?;
+    let (dir_location, name) = match method {
+        "PUT" => location.rsplit_once('/').unwrap_or(("", &location)),
+        _ => (location.as_str(), ""),
+    };
+    let upload = location_config.upload.clone().ok_or(405)?;
+
+    if !authorized(upload.auth.as_deref(), headers) {
+        return Err(401);
+    }
+
+    if headers.contains_key("transfer-encoding") {
+        return Err(411);
+    }
+    let length: u64 = headers
+        .get("content-length")
+        .and_then(|length| length.parse().ok())
+        .ok_or(411)?;
+    if length
+        > upload
+            .max_size
+            .map_or(100 * 1024 * 1024 /* 100 MB */, |size| size.0)
+    {
+        return Err(413);
+    }
+
+    let (root, dir_path) = location_root(dir_location)?;
+    let dir = resolve_path(
+        &root,
+        &dir_path,
+        config.server.symlinks.unwrap_or_default(),
+        config.server.deny_hidden.unwrap_or(false),
+    )?;
+    if !dir.is_dir() {
+        return Err(409);
+    }
+
+    let hide = location_config.index_hide.clone().unwrap_or_default();
+    let policy = upload.overwrite.unwrap_or_default();
+
+    if method == "PUT" {
+        let name = upload_name(name, &upload, &hide)?;
+        continue_upload(headers, reader).await?;
+
+        let mut body = reader.take(length);
+        let (name, replaced) = store(&dir, &name, policy, async |file| {
+            let copied = tokio::io::copy(&mut body, file).await.map_err(|_| 500)?;
+            if copied < length {
+                Err(400)
+            } else {
+                Ok(())
+            }
+        })
+        .await?;
+        invalidate(dir_location, &name).await;
+
+        let href = href(dir_location, &name);
+        return Ok((if replaced { 204 } else { 201 }, href));
+    }
+
+    let boundary = headers
+        .get("content-type")
+        .filter(|content_type| content_type.starts_with("multipart/form-data"))
+        .and_then(|content_type| header_param(content_type, "boundary"))
+        .ok_or(415)?;
+    continue_upload(headers, reader).await?;
+
+    // every file is written before any is moved into place, so that a
+    // request failing partway stores none of them
+    let mut multipart = Multipart::new(reader.take(length), &boundary);
+    let mut staged = vec![];
+    let result = async {
+        while let Some(part) = multipart.next_part().await? {
+            let filename = part
+                .get("content-disposition")
+                .and_then(|disposition| header_param(disposition, "filename"))
+                .filter(|filename| !filename.is_empty());
+            let Some(filename) = filename else {
+                multipart.copy_to(&mut tokio::io::sink()).await?;
+                continue;
+            };
+
+            // some browsers send the full client-side path
+            let filename = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+            let name = upload_name(filename, &upload, &hide)?;
+            staged.push(
+                stage(&dir, &name, policy, async |file| {
+                    multipart.copy_to(file).await
+                })
+                .await?,
+            );
+        }
+        Ok(())
+    }
+    .await;
+
+    if let Err(status_code) = result {
+        for file in staged {
+            file.discard().await;
+        }
+        return Err(status_code);
+    }
+    if staged.is_empty() {
+        return Err(400);
+    }
+
+    let mut staged = staged.into_iter();
+    while let Some(file) = staged.next() {
+        match file.commit().await {
+            Ok((name, _)) => invalidate(dir_location, &name).await,
+            Err(status_code) => {
+                for file in staged {
+                    file.discard().await;
+                }
+                return Err(status_code);
+            }
+        }
+    }
+    Ok((303, href(dir_location, "")))
+}
+
+/// Checks `Authorization: Basic` credentials against the `user:password`
+/// pairs in `auth`, if any are configured.
+pub fn authorized(auth: Option<&[String]>, headers: &HashMap<String, String>) -> bool {
+    let Some(users) = auth else {
+        return true;
+    };
+
+    headers
+        .get("authorization")
+        .and_then(|authorization| authorization.strip_prefix("Basic "))
+        .and_then(|credentials| STANDARD.decode(credentials.trim()).ok())
+        .is_some_and(|credentials| {
+            // every pair is compared in full, so timing tells nothing about
+            // which one came close
+            users.iter().fold(false, |found, user| {
+                found | constant_time_eq(user.as_bytes(), &credentials)
+            })
+        })
+}
+
+/// Compares in a time depending only on the length of `secret`.
+fn constant_time_eq(secret: &[u8], given: &[u8]) -> bool {
+    let mut diff = (secret.len() != given.len()) as u8;
+    for (i, byte) in secret.iter().enumerate() {
+        diff |= byte ^ given.get(i).copied().unwrap_or(!byte);
+    }
+    diff == 0
+}
+
+/// Validates an uploaded file name, refusing paths, dotfiles and hidden
+/// names with 403 and extensions outside `extensions` with 415.
+fn upload_name(name: &str, upload: &UploadConfig, hide: &HidePatterns) -> Result<String, i32> {
+    if name.is_empty() {
+        return Err(400);
+    }
+    if name.contains(['/', '\\', '\0']) || name.starts_with('.') || hide.is_match(name) {
+        return Err(403);
+    }
+
+    if let Some(extensions) = &upload.extensions {
+        let extension = Path::new(name)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default();
+        if !extensions.iter().any(|allowed| {
+            allowed
+                .trim_start_matches('.')
+                .eq_ignore_ascii_case(extension)
+        }) {
+            return Err(415);
+        }
+    }
+
+    Ok(name.to_owned())
+}
+
+/// Answers `Expect: 100-continue` once the upload has been accepted.
+pub async fn continue_upload<S>(
+    headers: &HashMap<String, String>,
+    reader: &mut BufReader<&mut S>,
+) -> Result<(), i32>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if headers
+        .get("expect")
+        .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+    {
+        reader
+            .get_mut()
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .await
+            .map_err(|_| 400)?;
+    }
+    Ok(())
+}
+
+/// Picks where `name` is stored in `dir` according to the overwrite policy,
+/// and whether an existing file is replaced.
+pub fn destination(dir: &Path, name: &str, policy: OverwritePolicy) -> Result<(String, bool), i32> {
+    let exists = |name: &str| dir.join(name).symlink_metadata().ok();
+
+    match exists(name) {
+        None => Ok((name.to_owned(), false)),
+        Some(meta) if !meta.is_file() => Err(409),
+        Some(_) => match policy {
+            OverwritePolicy::Deny => Err(409),
+            OverwritePolicy::Replace => Ok((name.to_owned(), true)),
+            OverwritePolicy::Rename => {
+                let (stem, extension) = match name.rsplit_once('.') {
+                    Some((stem, extension)) if !stem.is_empty() => {
+                        (stem, format!(".{}", extension))
+                    }
+                    _ => (name, String::new()),
+                };
+                (1..1000)
+                    .map(|n| format!("{} ({}){}", stem, n, extension))
+                    .find(|name| exists(name).is_none())
+                    .map(|name| (name, false))
+                    .ok_or(409)
+            }
+        },
+    }
+}
+
+/// A file written under a temporary name in the directory it goes to, to be
+/// moved into place by `commit` or removed by `discard`.
+pub struct Staged {
+    dir: PathBuf,
+    name: String,
+    temp: PathBuf,
+    policy: OverwritePolicy,
+}
+
+impl Staged {
+    /// Moves the file into place, picking its final name according to the
+    /// overwrite policy, and tells whether an existing file was replaced.
+    ///
+    /// Unless replacing, the file is hard linked to its name, which fails
+    /// rather than clobber a file created after the name was picked.
+    pub async fn commit(self) -> Result<(String, bool), i32> {
+        for _ in 0..COMMIT_ATTEMPTS {
+            // the target may have appeared while the body was read
+            let (name, replaced) = match destination(&self.dir, &self.name, self.policy) {
+                Ok(destination) => destination,
+                Err(status_code) => {
+                    self.discard().await;
+                    return Err(status_code);
+                }
+            };
+
+            let target = self.dir.join(&name);
+            let result = if self.policy == OverwritePolicy::Replace {
+                rename(&self.temp, &target).await
+            } else {
+                hard_link(&self.temp, &target).await
+            };
+            match result {
+                Ok(()) => {
+                    if self.policy != OverwritePolicy::Replace {
+                        let _ = remove_file(&self.temp).await;
+                    }
+                    return Ok((name, replaced));
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    if self.policy == OverwritePolicy::Rename {
+                        continue;
+                    }
+                    self.discard().await;
+                    return Err(409);
+                }
+                Err(_e) => {
+                    #[cfg(feature = "log")]
+                    error!("failed to store upload {}: {}", name, _e);
+                    self.discard().await;
+                    return Err(500);
+                }
+            }
+        }
+        self.discard().await;
+        Err(409)
+    }
+
+    pub async fn discard(self) {
+        let _ = remove_file(&self.temp).await;
+    }
+}
+
+/// Writes a file to a temporary name next to `name` in `dir`, refusing it
+/// up front when the overwrite policy would.
+pub async fn stage<F>(
+    dir: &Path,
+    name: &str,
+    policy: OverwritePolicy,
+    write: F,
+) -> Result<Staged, i32>
+where
+    F: AsyncFnOnce(&mut File) -> Result<(), i32>,
+{
+    destination(dir, name, policy)?;
+    let staged = Staged {
+        dir: dir.to_owned(),
+        name: name.to_owned(),
+        temp: dir.join(format!(
+            ".{}.{}-{}{}",
+            name,
+            process::id(),
+            UPLOAD_COUNTER.fetch_add(1, Ordering::Relaxed),
+            PARTIAL_UPLOAD
+        )),
+        policy,
+    };
+
+    let mut file = File::create_new(&staged.temp).await.map_err(|_| 500)?;
+    let result = match write(&mut file).await {
+        Ok(()) => file.sync_all().await.map_err(|_| 500),
+        Err(status_code) => Err(status_code),
+    };
+    drop(file);
+
+    match result {
+        Ok(()) => Ok(staged),
+        Err(status_code) => {
+            staged.discard().await;
+            Err(status_code)
+        }
+    }
+}
+
+/// Writes a file through a temporary file in the same directory, renamed
+/// into place only once `write` has succeeded.
+pub async fn store<F>(
+    dir: &Path,
+    name: &str,
+    policy: OverwritePolicy,
+    write: F,
+) -> Result<(String, bool), i32>
+where
+    F: AsyncFnOnce(&mut File) -> Result<(), i32>,
+{
+    stage(dir, name, policy, write).await?.commit().await
+}
+
+/// Drops the cached listing of `dir` and the cached copy of the file.
+#[cfg(feature = "lru_cache")]
+async fn invalidate(dir: &str, name: &str) {
+    let dir = dir.trim_matches('/');
+
+    let mut cache = INDEX_CACHE.write().await;
+    let stale: Vec<String> = cache
+        .iter()
+        .map(|(key, _)| key)
+        .filter(|key| key.splitn(8, ':').nth(7).map(|l| l.trim_matches('/')) == Some(dir))
+        .cloned()
+        .collect();
+    for key in stale {
+        cache.pop(&key);
+    }
+
+    let file = if dir.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}/{}", dir, name)
+    };
+
+    let mut cache = FILE_CACHE.write().await;
+    let stale: Vec<String> = cache
+        .iter()
+        .map(|(key, _)| key)
+        .filter(|key| key.split_once(':').map(|(_, l)| l) == Some(&file))
+        .cloned()
+        .collect();
+    for key in stale {
+        cache.pop(&key);
+    }
+    drop(cache);
+
+    // a new `name.html` serves the clean `name` that used to be missing
+    let mut cache = CLEAN_URL_CACHE.write().await;
+    for file in [Some(file.as_str()), file.strip_suffix(".html")]
+        .into_iter()
+        .flatten()
+    {
+        cache.pop(file);
+        cache.pop(&format!("{}/", file));
+    }
+}
+
+#[cfg(not(feature = "lru_cache"))]
+async fn invalidate(_dir: &str, _name: &str) {}
+
+#[inline]
+fn href(dir: &str, name: &str) -> String {
+    let mut href = String::from("/");
+    for part in dir.split('/').filter(|part| !part.is_empty()) {
+        href.push_str(&urlencoding::encode(part));
+        href.push('/');
+    }
+    href + &urlencoding::encode(name)
+}
+
+/// Reads a `key=value` or `key="value"` parameter of a header value.
+fn header_param(value: &str, key: &str) -> Option<String> {
+    value.split(';').skip(1).find_map(|param| {
+        let (k, v) = param.trim().split_once('=')?;
+        k.trim()
+            .eq_ignore_ascii_case(key)
+            .then(|| v.trim().trim_matches('"').to_owned())
+    })
+}
+
+/// A streaming `multipart/form-data` reader that never holds more than a
+/// chunk of a part in memory.
+pub struct Multipart<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    delimiter: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> Multipart<R> {
+    pub fn new(reader: R, boundary: &str) -> Self {
+        Multipart {
+            reader,
+            // lets the first boundary match like every later one
+            buffer: b"\r\n".to_vec(),
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+        }
+    }
+
+    async fn fill(&mut self) -> Result<(), i32> {
+        let mut chunk = vec![0; UPLOAD_CHUNK];
+        match self.reader.read(&mut chunk).await {
+            Ok(0) | Err(_) => Err(400),
+            Ok(n) => {
+                self.buffer.extend_from_slice(&chunk[..n]);
+                Ok(())
+            }
+        }
+    }
+
+    /// Skips to the next part and reads its headers, or returns `None` after
+    /// the closing boundary.
+    pub async fn next_part(&mut self) -> Result<Option<HashMap<String, String>>, i32> {
+        loop {
+            if let Some(i) = find(&self.buffer, &self.delimiter) {
+                self.buffer.drain(..i + self.delimiter.len());
+                break;
+            }
+            let keep = self.delimiter.len() - 1;
+            self.buffer.drain(..self.buffer.len().saturating_sub(keep));
+            self.fill().await?;
+        }
+
+        while self.buffer.len() < 2 {
+            self.fill().await?;
+        }
+        if self.buffer.starts_with(b"--") {
+            return Ok(None);
+        }
+
+        loop {
+            if let Some(i) = find(&self.buffer, b"\r\n\r\n") {
+                let headers = String::from_utf8_lossy(&self.buffer[..i])
+                    .split("\r\n")
+                    .filter_map(|line| line.split_once(':'))
+                    .map(|(k, v)| (k.trim().to_ascii_lowercase(), v.trim().to_owned()))
+                    .collect();
+                self.buffer.drain(..i + 4);
+                return Ok(Some(headers));
+            }
+            if self.buffer.len() > MAX_PART_HEADERS {
+                return Err(400);
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// Copies the body of the current part up to the next boundary.
+    pub async fn copy_to<W: AsyncWrite + Unpin>(&mut self, writer: &mut W) -> Result<(), i32> {
+        loop {
+            if let Some(i) = find(&self.buffer, &self.delimiter) {
+                writer.write_all(&self.buffer[..i]).await.map_err(|_| 500)?;
+                self.buffer.drain(..i);
+                return Ok(());
+            }
+
+            let keep = self.delimiter.len() - 1;
+            if self.buffer.len() > keep {
+                let n = self.buffer.len() - keep;
+                writer.write_all(&self.buffer[..n]).await.map_err(|_| 500)?;
+                self.buffer.drain(..n);
+            }
+            self.fill().await?;
+        }
+    }
+}
+
+#[inline]
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
@@ -0,0 +1,1171 @@
+use crate::{
+    config::{LocationConfig, OverwritePolicy, WebdavConfig, CONFIG},
+    init::DATE_FORMAT,
+    route::{
+        html_escape, index_entry, location_config, location_match, normalize_location,
+        read_entries, resolve_path, IndexEntry,
+    },
+    server::reason,
+    storage::{is_site_rules, location_root, LocalStorage},
+    upload::{authorized, continue_upload, store},
+};
+use chrono::DateTime;
+use lazy_static::lazy_static;
+use quick_xml::{
+    events::Event,
+    name::{QName, ResolveResult},
+    reader::NsReader,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    io,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::{self, create_dir, remove_dir_all, remove_file, rename},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, BufReader},
+    task::spawn_blocking,
+};
+
+#[cfg(feature = "lru_cache")]
+use crate::init::{CLEAN_URL_CACHE, FILE_CACHE, INDEX_CACHE};
+
+/// Methods answered in a `webdav` location.
+pub const WEBDAV_METHODS: &str =
+    "OPTIONS, GET, HEAD, PUT, DELETE, MKCOL, COPY, MOVE, PROPFIND, PROPPATCH, LOCK, UNLOCK";
+
+/// Methods answered in a `read_only` `webdav` location.
+const WEBDAV_READ_METHODS: &str = "OPTIONS, GET, HEAD, PROPFIND";
+
+/// Largest PROPFIND, PROPPATCH or LOCK body accepted.
+const MAX_XML_BODY: u64 = 1024 * 1024;
+
+/// Most resources listed by a `Depth: infinity` PROPFIND.
+const MAX_PROPFIND_ENTRIES: usize = 100_000;
+
+const DEFAULT_LOCK_TIMEOUT: u64 = 3600;
+const MAX_LOCK_TIMEOUT: u64 = 86400;
+
+const LIVE_PROPS: [&str; 9] = [
+    "creationdate",
+    "displayname",
+    "getcontentlength",
+    "getcontenttype",
+    "getetag",
+    "getlastmodified",
+    "lockdiscovery",
+    "resourcetype",
+    "supportedlock",
+];
+
+static LOCK_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// Active locks by token.
+    static ref LOCKS: Mutex<HashMap<String, Lock>> = Mutex::new(HashMap::new());
+    /// Properties set through PROPPATCH, by location.
+    static ref DEAD_PROPS: Mutex<HashMap<String, BTreeMap<PropName, String>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// A WebDAV answer, with a multistatus or lock body when there is one.
+pub struct DavResponse {
+    pub status_code: i32,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Option<String>,
+}
+
+impl DavResponse {
+    #[inline]
+    fn new(status_code: i32) -> Self {
+        DavResponse {
+            status_code,
+            headers: vec![],
+            body: None,
+        }
+    }
+
+    #[inline]
+    fn xml(status_code: i32, body: String) -> Self {
+        DavResponse {
+            status_code,
+            headers: vec![],
+            body: Some(format!(
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n{}",
+                body
+            )),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct PropName {
+    pub ns: String,
+    pub name: String,
+}
+
+impl PropName {
+    #[inline]
+    fn is_dav(&self, name: &str) -> bool {
+        self.ns == "DAV:" && self.name == name
+    }
+
+    /// Renders the property as an element holding `value`.
+    fn element(&self, value: &str) -> String {
+        let (open, close) = if self.ns == "DAV:" {
+            (format!("D:{}", self.name), format!("D:{}", self.name))
+        } else {
+            (
+                format!("x:{} xmlns:x=\"{}\"", self.name, html_escape(&self.ns)),
+                format!("x:{}", self.name),
+            )
+        };
+        if value.is_empty() {
+            format!("<{}/>", open)
+        } else {
+            format!("<{}>{}</{}>", open, value, close)
+        }
+    }
+}
+
+struct Lock {
+    location: String,
+    infinite: bool,
+    exclusive: bool,
+    owner: Option<String>,
+    timeout: u64,
+    expires: Instant,
+}
+
+impl Lock {
+    /// Whether the lock applies to the resource at `location`.
+    #[inline]
+    fn covers(&self, location: &str) -> bool {
+        self.location == location || (self.infinite && is_under(location, &self.location))
+    }
+
+    fn active(&self, token: &str) -> String {
+        format!(
+            "<D:activelock><D:locktype><D:write/></D:locktype><D:lockscope>{scope}</D:lockscope>\
+<D:depth>{depth}</D:depth>{owner}<D:timeout>Second-{timeout}</D:timeout>\
+<D:locktoken><D:href>{token}</D:href></D:locktoken>\
+<D:lockroot><D:href>{root}</D:href></D:lockroot></D:activelock>",
+            scope = if self.exclusive {
+                "<D:exclusive/>"
+            } else {
+                "<D:shared/>"
+            },
+            depth = if self.infinite { "infinity" } else { "0" },
+            owner = self
+                .owner
+                .as_ref()
+                .map(|owner| format!("<D:owner>{}</D:owner>", owner))
+                .unwrap_or_default(),
+            timeout = self.timeout,
+            root = dav_href(&self.location, false),
+        )
+    }
+}
+
+/// The `webdav` config of the location, if it is served over WebDAV.
+#[inline]
+pub fn webdav_config(location: &str) -> Option<WebdavConfig> {
+    location_config(location).and_then(|l| l.webdav)
+}
+
+/// Answers a WebDAV request other than GET in a `webdav` location, which
+/// `location_config` applies to.
+///
+/// Paths are resolved like GET requests against `server.root`, and
+/// collections are enumerated like directory listings.
+pub async fn webdav<S>(
+    method: &str,
+    location: &str,
+    location_config: &LocationConfig,
+    headers: &HashMap<String, String>,
+    reader: &mut BufReader<&mut S>,
+) -> Result<DavResponse, i32>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let location = normalize_location(location)?;
+    let location = location.as_str();
+    let dav = location_config.webdav.clone().ok_or(405)?;
+    let read_only = dav.read_only.unwrap_or(false);
+
+    if method == "OPTIONS" {
+        let mut response = DavResponse::new(200);
+        response.headers = vec![
+            ("DAV", "1, 2".to_owned()),
+            ("MS-Author-Via", "DAV".to_owned()),
+            (
+                "Allow",
+                if read_only {
+                    WEBDAV_READ_METHODS
+                } else {
+                    WEBDAV_METHODS
+                }
+                .to_owned(),
+            ),
+        ];
+        return Ok(response);
+    }
+
+    if !authorized(dav.auth.as_deref(), headers) {
+        return Err(401);
+    }
+    if read_only && method != "PROPFIND" {
+        let mut response = DavResponse::new(405);
+        response.headers = vec![("Allow", WEBDAV_READ_METHODS.to_owned())];
+        return Ok(response);
+    }
+
+    let response = match method {
+        "PROPFIND" => propfind(location, headers, &read_body(headers, reader).await?).await,
+        "PROPPATCH" => proppatch(location, headers, &read_body(headers, reader).await?),
+        "MKCOL" => mkcol(location, headers).await,
+        "PUT" => put(location, headers, &dav, reader).await,
+        "DELETE" => delete(location, headers).await,
+        "COPY" | "MOVE" => copy_or_move(method, location, headers).await,
+        "LOCK" => lock(location, headers, &read_body(headers, reader).await?).await,
+        "UNLOCK" => unlock(location, headers),
+        _ => {
+            let mut response = DavResponse::new(405);
+            response.headers = vec![("Allow", WEBDAV_METHODS.to_owned())];
+            Ok(response)
+        }
+    }?;
+
+    if matches!(response.status_code, 201 | 204) {
+        clear_caches().await;
+    }
+    Ok(response)
+}
+
+async fn propfind(
+    location: &str,
+    headers: &HashMap<String, String>,
+    body: &str,
+) -> Result<DavResponse, i32> {
+    let find = parse_propfind(body)?;
+    let path = resolve(location)?;
+    let depth = match headers.get("depth").map(|depth| depth.as_str()) {
+        Some("0") => 0,
+        Some("1") => 1,
+        _ => usize::MAX,
+    };
+
+    let name = location.rsplit('/').next().unwrap_or_default().to_owned();
+    let entry = index_entry(&path, name).await.map_err(|_| 404)?;
+
+    let mut multistatus = String::from("<D:multistatus xmlns:D=\"DAV:\">\n");
+    let mut pending = vec![(location.to_owned(), path, entry, 0)];
+    let mut visited = vec![];
+    let mut count = 0;
+
+    while let Some((location, path, entry, level)) = pending.pop() {
+        multistatus.push_str(&prop_response(&location, &entry, &find));
+        count += 1;
+        if count > MAX_PROPFIND_ENTRIES {
+            return Err(403);
+        }
+
+        if !entry.is_dir() || level >= depth || visited.contains(&path) {
+            continue;
+        }
+        visited.push(path.clone());
+
+        let location_config = location_config(&location).unwrap_or_default();
+        let (root, dir_path) = location_root(&location)?;
+        let storage = LocalStorage::new(&root);
+        let entries = read_entries(&storage, &dir_path, &location, &location_config, None)
+            .await
+            .map_err(|_| 403)?;
+        for entry in entries {
+            let child = join_location(&location, &entry.name);
+            if let Ok(path) = resolve(&child) {
+                pending.push((child, path, entry, level + 1));
+            }
+        }
+    }
+
+    multistatus.push_str("</D:multistatus>\n");
+    Ok(DavResponse::xml(207, multistatus))
+}
+
+/// Renders the `<D:response>` of one resource for a PROPFIND.
+fn prop_response(location: &str, entry: &IndexEntry, find: &PropFind) -> String {
+    let dead = DEAD_PROPS
+        .lock()
+        .unwrap()
+        .get(location)
+        .cloned()
+        .unwrap_or_default();
+    let live = |name: &str| live_prop(name, location, entry);
+
+    let mut found = String::new();
+    let mut missing = String::new();
+    match find {
+        PropFind::AllProp => {
+            for name in LIVE_PROPS {
+                found.push_str(&live(name).unwrap_or_default());
+            }
+            for (name, value) in &dead {
+                found.push_str(&name.element(value));
+            }
+        }
+        PropFind::PropName => {
+            for name in LIVE_PROPS {
+                if live(name).is_some() {
+                    write!(found, "<D:{}/>", name).unwrap();
+                }
+            }
+            for name in dead.keys() {
+                found.push_str(&name.element(""));
+            }
+        }
+        PropFind::Prop(names) => {
+            for name in names {
+                let value = if name.ns == "DAV:" {
+                    live(&name.name)
+                } else {
+                    None
+                }
+                .or_else(|| dead.get(name).map(|value| name.element(value)));
+                match value {
+                    Some(value) => found.push_str(&value),
+                    None => missing.push_str(&name.element("")),
+                }
+            }
+        }
+    }
+
+    let mut response = format!(
+        "<D:response><D:href>{}</D:href>",
+        dav_href(location, entry.is_dir())
+    );
+    for (props, status_code) in [(found, 200), (missing, 404)] {
+        if !props.is_empty() {
+            write!(
+                response,
+                "<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 {} {}</D:status></D:propstat>",
+                props,
+                status_code,
+                reason(status_code)
+            )
+            .unwrap();
+        }
+    }
+    response.push_str("</D:response>\n");
+    response
+}
+
+fn live_prop(name: &str, location: &str, entry: &IndexEntry) -> Option<String> {
+    let dir = entry.is_dir();
+    let mtime = DateTime::from_timestamp(entry.mtime, 0).unwrap_or_default();
+
+    let value = match name {
+        "creationdate" => mtime.to_rfc3339(),
+        "displayname" => html_escape(&entry.name),
+        "getcontentlength" if !dir => entry.size.to_string(),
+        "getcontenttype" if !dir => html_escape(entry.mime.as_deref().unwrap_or_default()),
+        "getetag" if !dir => html_escape(&entry.etag()),
+        "getlastmodified" => mtime.format(DATE_FORMAT).to_string(),
+        "lockdiscovery" => active_locks(location),
+        "resourcetype" if dir => "<D:collection/>".to_owned(),
+        "resourcetype" => String::new(),
+        "supportedlock" => "<D:lockentry><D:lockscope><D:exclusive/></D:lockscope>\
+<D:locktype><D:write/></D:locktype></D:lockentry>\
+<D:lockentry><D:lockscope><D:shared/></D:lockscope>\
+<D:locktype><D:write/></D:locktype></D:lockentry>"
+            .to_owned(),
+        _ => return None,
+    };
+
+    Some(if value.is_empty() {
+        format!("<D:{}/>", name)
+    } else {
+        format!("<D:{}>{}</D:{}>", name, value, name)
+    })
+}
+
+fn proppatch(
+    location: &str,
+    headers: &HashMap<String, String>,
+    body: &str,
+) -> Result<DavResponse, i32> {
+    resolve(location)?;
+    check_locks(location, headers, false)?;
+    let updates = parse_proppatch(body)?;
+
+    // DAV: properties are computed, and a failed update fails them all
+    let protected = updates.iter().any(|(name, _)| name.ns == "DAV:");
+    let mut props: BTreeMap<i32, String> = BTreeMap::new();
+    for (name, _) in &updates {
+        let status_code = match (protected, name.ns == "DAV:") {
+            (true, true) => 403,
+            (true, false) => 424,
+            _ => 200,
+        };
+        props
+            .entry(status_code)
+            .or_default()
+            .push_str(&name.element(""));
+    }
+
+    if !protected {
+        let mut dead = DEAD_PROPS.lock().unwrap();
+        let props = dead.entry(location.to_owned()).or_default();
+        for (name, value) in updates {
+            match value {
+                Some(value) => props.insert(name, value),
+                None => props.remove(&name),
+            };
+        }
+    }
+
+    let mut multistatus = format!(
+        "<D:multistatus xmlns:D=\"DAV:\">\n<D:response><D:href>{}</D:href>",
+        dav_href(location, resolve(location)?.is_dir())
+    );
+    for (status_code, props) in props {
+        write!(
+            multistatus,
+            "<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 {} {}</D:status></D:propstat>",
+            props,
+            status_code,
+            reason(status_code)
+        )
+        .unwrap();
+    }
+    multistatus.push_str("</D:response>\n</D:multistatus>\n");
+    Ok(DavResponse::xml(207, multistatus))
+}
+
+async fn mkcol(location: &str, headers: &HashMap<String, String>) -> Result<DavResponse, i32> {
+    if headers
+        .get("content-length")
+        .is_some_and(|length| length != "0")
+    {
+        return Err(415);
+    }
+
+    let path = target_path(location)?;
+    if path.symlink_metadata().is_ok() {
+        return Err(405);
+    }
+    check_locks(location, headers, false)?;
+
+    create_dir(&path).await.map_err(|_| 500)?;
+    Ok(DavResponse::new(201))
+}
+
+async fn put<S>(
+    location: &str,
+    headers: &HashMap<String, String>,
+    dav: &WebdavConfig,
+    reader: &mut BufReader<&mut S>,
+) -> Result<DavResponse, i32>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let path = target_path(location)?;
+    if path.is_dir() {
+        return Err(405);
+    }
+    check_locks(location, headers, false)?;
+
+    if headers.contains_key("transfer-encoding") {
+        return Err(411);
+    }
+    let length: u64 = headers
+        .get("content-length")
+        .and_then(|length| length.parse().ok())
+        .ok_or(411)?;
+    if length
+        > dav
+            .max_size
+            .map_or(1024 * 1024 * 1024 /* 1 GB */, |size| size.0)
+    {
+        return Err(413);
+    }
+    continue_upload(headers, reader).await?;
+
+    let (dir, name) = split_path(&path);
+    let mut body = reader.take(length);
+    let (_, replaced) = store(dir, name, OverwritePolicy::Replace, async |file| {
+        let copied = tokio::io::copy(&mut body, file).await.map_err(|_| 500)?;
+        if copied < length {
+            Err(400)
+        } else {
+            Ok(())
+        }
+    })
+    .await?;
+
+    Ok(DavResponse::new(if replaced { 204 } else { 201 }))
+}
+
+async fn delete(location: &str, headers: &HashMap<String, String>) -> Result<DavResponse, i32> {
+    resolve(location)?;
+    if is_root(location) {
+        return Err(403);
+    }
+    let path = target_path(location)?;
+    check_locks(location, headers, true)?;
+
+    remove_path(&path).await.map_err(|_| 500)?;
+    forget(location);
+    Ok(DavResponse::new(204))
+}
+
+async fn copy_or_move(
+    method: &str,
+    location: &str,
+    headers: &HashMap<String, String>,
+) -> Result<DavResponse, i32> {
+    let source = resolve(location)?;
+    let destination = headers
+        .get("destination")
+        .and_then(|destination| destination_location(destination))
+        .ok_or(400)?;
+
+    // the destination may belong to another location, with its own rules
+    let dav = webdav_config(&destination).ok_or(403)?;
+    if dav.read_only.unwrap_or(false) {
+        return Err(403);
+    }
+    if !authorized(dav.auth.as_deref(), headers) {
+        return Err(401);
+    }
+    // replacing an ancestor would remove the source before it is copied
+    if destination == location
+        || is_under(&destination, location)
+        || is_under(location, &destination)
+        || is_root(&destination)
+        || (method == "MOVE" && is_root(location))
+    {
+        return Err(403);
+    }
+
+    let target = target_path(&destination)?;
+    check_locks(&destination, headers, true)?;
+    if method == "MOVE" {
+        check_locks(location, headers, true)?;
+    }
+
+    let existed = target.symlink_metadata().is_ok();
+    if existed
+        && headers
+            .get("overwrite")
+            .is_some_and(|overwrite| overwrite.eq_ignore_ascii_case("F"))
+    {
+        return Err(412);
+    }
+
+    let recursive = headers.get("depth").is_none_or(|depth| depth != "0");
+    let size = tree_size(source.clone(), method == "MOVE" || recursive)
+        .await
+        .map_err(|_| 500)?;
+    if size
+        > dav
+            .max_size
+            .map_or(1024 * 1024 * 1024 /* 1 GB */, |size| size.0)
+    {
+        return Err(413);
+    }
+    if existed {
+        remove_path(&target).await.map_err(|_| 500)?;
+        forget(&destination);
+    }
+
+    let result = if method == "MOVE" {
+        let source = target_path(location)?;
+        match rename(&source, &target).await {
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                match copy_path(source.clone(), target, true).await {
+                    Ok(()) => remove_path(&source).await,
+                    Err(e) => Err(e),
+                }
+            }
+            result => result,
+        }
+    } else {
+        copy_path(source, target, recursive).await
+    };
+    result.map_err(|_| 500)?;
+
+    carry_props(location, &destination, method == "MOVE", recursive);
+    if method == "MOVE" {
+        forget(location);
+    }
+    Ok(DavResponse::new(if existed { 204 } else { 201 }))
+}
+
+async fn lock(
+    location: &str,
+    headers: &HashMap<String, String>,
+    body: &str,
+) -> Result<DavResponse, i32> {
+    let timeout = lock_timeout(headers);
+
+    if body.trim().is_empty() {
+        // refreshes a lock named in the If header
+        let tokens = submitted_tokens(headers);
+        let mut locks = LOCKS.lock().unwrap();
+        let (token, lock) = locks
+            .iter_mut()
+            .find(|(token, lock)| tokens.contains(token) && lock.covers(location))
+            .ok_or(412)?;
+        lock.timeout = timeout;
+        lock.expires = Instant::now() + Duration::from_secs(timeout);
+
+        return Ok(DavResponse::xml(
+            200,
+            format!(
+                "<D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>{}</D:lockdiscovery></D:prop>\n",
+                lock.active(token)
+            ),
+        ));
+    }
+
+    let (exclusive, owner) = parse_lockinfo(body)?;
+    let infinite = match headers.get("depth").map(|depth| depth.as_str()) {
+        Some("0") => false,
+        Some("1") => return Err(400),
+        _ => true,
+    };
+
+    let path = target_path(location)?;
+    let created = path.symlink_metadata().is_err();
+    if created {
+        fs::write(&path, b"").await.map_err(|_| 500)?;
+    }
+
+    let token = format!(
+        "opaquelocktoken:{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as u32,
+        process::id() as u16,
+        LOCK_COUNTER.fetch_add(1, Ordering::Relaxed) as u16,
+        0x8000,
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64
+            & 0xffff_ffff_ffff,
+    );
+    let lock = Lock {
+        location: location.to_owned(),
+        infinite,
+        exclusive,
+        owner,
+        timeout,
+        expires: Instant::now() + Duration::from_secs(timeout),
+    };
+
+    let mut locks = LOCKS.lock().unwrap();
+    prune(&mut locks);
+    let conflict = locks.values().any(|other| {
+        (other.covers(location) || (infinite && is_under(&other.location, location)))
+            && (other.exclusive || exclusive)
+    });
+    if conflict {
+        return Err(423);
+    }
+
+    let body = format!(
+        "<D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>{}</D:lockdiscovery></D:prop>\n",
+        lock.active(&token)
+    );
+    locks.insert(token.clone(), lock);
+
+    let mut response = DavResponse::xml(if created { 201 } else { 200 }, body);
+    response.headers = vec![("Lock-Token", format!("<{}>", token))];
+    Ok(response)
+}
+
+fn unlock(location: &str, headers: &HashMap<String, String>) -> Result<DavResponse, i32> {
+    let token = headers
+        .get("lock-token")
+        .map(|token| token.trim().trim_start_matches('<').trim_end_matches('>'))
+        .ok_or(400)?;
+
+    let mut locks = LOCKS.lock().unwrap();
+    match locks.get(token) {
+        Some(lock) if lock.covers(location) => {
+            locks.remove(token);
+            Ok(DavResponse::new(204))
+        }
+        _ => Err(409),
+    }
+}
+
+/// Fails with 423 when a lock applies to `location`, or with `deep` to
+/// anything below it, and its token is not in the If header.
+pub fn check_locks(
+    location: &str,
+    headers: &HashMap<String, String>,
+    deep: bool,
+) -> Result<(), i32> {
+    let tokens = submitted_tokens(headers);
+    let mut locks = LOCKS.lock().unwrap();
+    prune(&mut locks);
+
+    let locked = locks.iter().any(|(token, lock)| {
+        (lock.covers(location) || (deep && is_under(&lock.location, location)))
+            && !tokens.contains(token)
+    });
+    if locked {
+        Err(423)
+    } else {
+        Ok(())
+    }
+}
+
+#[inline]
+fn prune(locks: &mut HashMap<String, Lock>) {
+    let now = Instant::now();
+    locks.retain(|_, lock| lock.expires > now);
+}
+
+/// Lock tokens named anywhere in the If header.
+fn submitted_tokens(headers: &HashMap<String, String>) -> Vec<String> {
+    headers
+        .get("if")
+        .map(|header| {
+            header
+                .split('<')
+                .skip(1)
+                .filter_map(|part| part.split_once('>'))
+                .map(|(token, _)| token.to_owned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn lock_timeout(headers: &HashMap<String, String>) -> u64 {
+    headers
+        .get("timeout")
+        .and_then(|timeout| {
+            timeout.split(',').find_map(|timeout| {
+                let timeout = timeout.trim();
+                if timeout.eq_ignore_ascii_case("infinite") {
+                    Some(MAX_LOCK_TIMEOUT)
+                } else {
+                    timeout.strip_prefix("Second-")?.parse().ok()
+                }
+            })
+        })
+        .unwrap_or(DEFAULT_LOCK_TIMEOUT)
+        .min(MAX_LOCK_TIMEOUT)
+}
+
+fn active_locks(location: &str) -> String {
+    let mut locks = LOCKS.lock().unwrap();
+    prune(&mut locks);
+    locks
+        .iter()
+        .filter(|(_, lock)| lock.covers(location))
+        .map(|(token, lock)| lock.active(token))
+        .collect()
+}
+
+/// Drops the locks and properties of `location` and everything below it.
+fn forget(location: &str) {
+    let gone = |other: &str| other == location || is_under(other, location);
+    LOCKS
+        .lock()
+        .unwrap()
+        .retain(|_, lock| !gone(&lock.location));
+    DEAD_PROPS.lock().unwrap().retain(|other, _| !gone(other));
+}
+
+/// Gives `to` the properties set on `from`, and on everything below it when
+/// `deep`, taking them away from `from` when it was moved.
+fn carry_props(from: &str, to: &str, moved: bool, deep: bool) {
+    let mut dead = DEAD_PROPS.lock().unwrap();
+    let carried: Vec<String> = dead
+        .keys()
+        .filter(|other| *other == from || (deep && is_under(other, from)))
+        .cloned()
+        .collect();
+    for other in carried {
+        let props = if moved {
+            dead.remove(&other)
+        } else {
+            dead.get(&other).cloned()
+        };
+        if let Some(props) = props {
+            dead.insert(format!("{}{}", to, &other[from.len()..]), props);
+        }
+    }
+}
+
+#[cfg(feature = "lru_cache")]
+async fn clear_caches() {
+    INDEX_CACHE.write().await.clear();
+    FILE_CACHE.write().await.clear();
+    CLEAN_URL_CACHE.write().await.clear();
+}
+
+#[cfg(not(feature = "lru_cache"))]
+async fn clear_caches() {}
+
+/// Resolves an existing resource like a GET request would.
+#[inline]
+fn resolve(location: &str) -> Result<PathBuf, i32> {
+    if is_site_rules(location) {
+        return Err(404);
+    }
+    let config = CONFIG.load();
+    let (root, path) = location_root(location)?;
+    resolve_path(
+        &root,
+        &path,
+        config.server.symlinks.unwrap_or_default(),
+        config.server.deny_hidden.unwrap_or(false),
+    )
+}
+
+/// Where the resource at `location` lives or would be created, without
+/// following a symlink in its last component.
+///
+/// The parent must be an existing directory (409), and the name must not be
+/// hidden (403).
+fn target_path(location: &str) -> Result<PathBuf, i32> {
+    let (parent, name) = location.rsplit_once('/').unwrap_or(("", location));
+    if name.is_empty() {
+        return Err(403);
+    }
+
+    let dir = resolve(parent).map_err(|status_code| match status_code {
+        404 => 409,
+        status_code => status_code,
+    })?;
+    if !dir.is_dir() {
+        return Err(409);
+    }
+
+    let hide = location_config(parent)
+        .and_then(|l| l.index_hide)
+        .unwrap_or_default();
+    let deny_hidden = CONFIG.load().server.deny_hidden.unwrap_or(false);
+    if hide.is_match(name) || (deny_hidden && name.starts_with('.')) || is_site_rules(location) {
+        return Err(403);
+    }
+
+    Ok(dir.join(name))
+}
+
+/// Whether `location` is the directory a location maps or the root of a
+/// WebDAV share, which can be neither removed nor replaced.
+fn is_root(location: &str) -> bool {
+    let parent = location.rsplit_once('/').map_or("", |(parent, _)| parent);
+    location.is_empty()
+        || location_match(location).is_some_and(|matched| matched.prefix == location)
+        || webdav_config(parent).is_none()
+}
+
+/// Bytes in the files of a file or, `recursive`ly, a directory tree, not
+/// following symlinks.
+async fn tree_size(path: PathBuf, recursive: bool) -> io::Result<u64> {
+    fn size(path: &Path, recursive: bool) -> io::Result<u64> {
+        let meta = std::fs::symlink_metadata(path)?;
+        if !meta.is_dir() {
+            return Ok(if meta.is_file() { meta.len() } else { 0 });
+        }
+        let mut total = 0u64;
+        if recursive {
+            for entry in std::fs::read_dir(path)? {
+                total = total.saturating_add(size(&entry?.path(), true)?);
+            }
+        }
+        Ok(total)
+    }
+
+    spawn_blocking(move || size(&path, recursive)).await?
+}
+
+#[inline]
+fn split_path(path: &Path) -> (&Path, &str) {
+    (
+        path.parent().unwrap_or(Path::new("/")),
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default(),
+    )
+}
+
+async fn remove_path(path: &Path) -> io::Result<()> {
+    if path.symlink_metadata()?.is_dir() {
+        remove_dir_all(path).await
+    } else {
+        remove_file(path).await
+    }
+}
+
+/// Copies a file or, `recursive`ly, a directory tree. Symlinks inside the
+/// tree are not followed.
+async fn copy_path(from: PathBuf, to: PathBuf, recursive: bool) -> io::Result<()> {
+    fn copy(from: &Path, to: &Path, recursive: bool) -> io::Result<()> {
+        let meta = std::fs::symlink_metadata(from)?;
+        if meta.is_dir() {
+            std::fs::create_dir(to)?;
+            if recursive {
+                for entry in std::fs::read_dir(from)? {
+                    let entry = entry?;
+                    copy(&entry.path(), &to.join(entry.file_name()), true)?;
+                }
+            }
+        } else if meta.is_file() {
+            std::fs::copy(from, to)?;
+        }
+        Ok(())
+    }
+
+    spawn_blocking(move || copy(&from, &to, recursive)).await?
+}
+
+/// Reads the location out of a `Destination` header, an absolute URI or an
+/// absolute path.
+pub fn destination_location(destination: &str) -> Option<String> {
+    let path = match destination.split_once("://") {
+        Some((_, rest)) => &rest[rest.find('/').unwrap_or(rest.len())..],
+        None => destination,
+    };
+    let path = path.split('?').next().unwrap_or_default();
+    let path = urlencoding::decode(path).ok()?;
+    normalize_location(&path).ok()
+}
+
+#[inline]
+pub fn is_under(location: &str, ancestor: &str) -> bool {
+    ancestor.is_empty() && !location.is_empty()
+        || location
+            .strip_prefix(ancestor)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+#[inline]
+fn join_location(location: &str, name: &str) -> String {
+    if location.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}/{}", location, name)
+    }
+}
+
+fn dav_href(location: &str, dir: bool) -> String {
+    let mut href = String::from("/");
+    for (i, part) in location
+        .split('/')
+        .filter(|part| !part.is_empty())
+        .enumerate()
+    {
+        if i > 0 {
+            href.push('/');
+        }
+        href.push_str(&urlencoding::encode(part));
+    }
+    if dir && href.len() > 1 {
+        href.push('/');
+    }
+    href
+}
+
+/// Reads the XML body of a PROPFIND, PROPPATCH or LOCK request.
+async fn read_body<S>(
+    headers: &HashMap<String, String>,
+    reader: &mut BufReader<&mut S>,
+) -> Result<String, i32>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if headers.contains_key("transfer-encoding") {
+        return Err(411);
+    }
+    let length: u64 = headers
+        .get("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    if length > MAX_XML_BODY {
+        return Err(413);
+    }
+    if length == 0 {
+        return Ok(String::new());
+    }
+    continue_upload(headers, reader).await?;
+
+    let mut body = vec![0; length as usize];
+    reader.read_exact(&mut body).await.map_err(|_| 400)?;
+    String::from_utf8(body).map_err(|_| 400)
+}
+
+#[derive(PartialEq, Debug)]
+pub enum PropFind {
+    AllProp,
+    PropName,
+    Prop(Vec<PropName>),
+}
+
+enum Node {
+    Element {
+        name: PropName,
+        qname: String,
+        empty: bool,
+    },
+    Close,
+    Eof,
+}
+
+fn next_node(reader: &mut NsReader<&[u8]>) -> Result<Node, i32> {
+    loop {
+        let (ns, event) = reader.read_resolved_event().map_err(|_| 400)?;
+        let ns = match ns {
+            ResolveResult::Bound(ns) => ns.0.to_owned(),
+            _ => String::new(),
+        };
+
+        return Ok(match event {
+            Event::Start(e) => Node::Element {
+                name: PropName {
+                    ns,
+                    name: e.local_name().as_ref().to_owned(),
+                },
+                qname: e.name().0.to_owned(),
+                empty: false,
+            },
+            Event::Empty(e) => Node::Element {
+                name: PropName {
+                    ns,
+                    name: e.local_name().as_ref().to_owned(),
+                },
+                qname: e.name().0.to_owned(),
+                empty: true,
+            },
+            Event::End(_) => Node::Close,
+            Event::Eof => Node::Eof,
+            _ => continue,
+        });
+    }
+}
+
+/// Reads what a PROPFIND asks for, all properties when the body is empty.
+pub fn parse_propfind(body: &str) -> Result<PropFind, i32> {
+    if body.trim().is_empty() {
+        return Ok(PropFind::AllProp);
+    }
+
+    let mut reader = NsReader::from_str(body);
+    let mut find = None;
+    let mut depth: usize = 0;
+    loop {
+        match next_node(&mut reader)? {
+            Node::Element { name, empty, .. } => {
+                match depth {
+                    0 if !name.is_dav("propfind") => return Err(400),
+                    1 if name.is_dav("allprop") => find = Some(PropFind::AllProp),
+                    1 if name.is_dav("propname") => find = Some(PropFind::PropName),
+                    1 if name.is_dav("prop") => find = Some(PropFind::Prop(vec![])),
+                    2 => {
+                        if let Some(PropFind::Prop(names)) = &mut find {
+                            names.push(name);
+                        }
+                    }
+                    _ => {}
+                }
+                if !empty {
+                    depth += 1;
+                }
+            }
+            Node::Close => depth = depth.saturating_sub(1),
+            Node::Eof => break,
+        }
+    }
+
+    find.ok_or(400)
+}
+
+/// Reads the properties a PROPPATCH sets, with their raw XML values, and
+/// removes, without one.
+pub fn parse_proppatch(body: &str) -> Result<Vec<(PropName, Option<String>)>, i32> {
+    let mut reader = NsReader::from_str(body);
+    let mut updates = vec![];
+    let mut set = false;
+    let mut depth: usize = 0;
+    loop {
+        match next_node(&mut reader)? {
+            Node::Element { name, qname, empty } => {
+                match depth {
+                    0 if !name.is_dav("propertyupdate") => return Err(400),
+                    1 => set = name.is_dav("set"),
+                    3 => {
+                        let value = if empty {
+                            String::new()
+                        } else {
+                            let span = reader.read_to_end(QName(&qname)).map_err(|_| 400)?;
+                            body[span.start as usize..span.end as usize].to_owned()
+                        };
+                        updates.push((name, set.then_some(value)));
+                        continue;
+                    }
+                    _ => {}
+                }
+                if !empty {
+                    depth += 1;
+                }
+            }
+            Node::Close => depth = depth.saturating_sub(1),
+            Node::Eof => break,
+        }
+    }
+
+    if updates.is_empty() {
+        Err(400)
+    } else {
+        Ok(updates)
+    }
+}
+
+/// Reads the scope and owner of a LOCK request.
+pub fn parse_lockinfo(body: &str) -> Result<(bool, Option<String>), i32> {
+    let mut reader = NsReader::from_str(body);
+    let mut exclusive = true;
+    let mut owner = None;
+    let mut scope = false;
+    let mut depth: usize = 0;
+    loop {
+        match next_node(&mut reader)? {
+            Node::Element { name, qname, empty } => {
+                match depth {
+                    0 if !name.is_dav("lockinfo") => return Err(400),
+                    1 if name.is_dav("owner") && !empty => {
+                        let span = reader.read_to_end(QName(&qname)).map_err(|_| 400)?;
+                        owner = Some(body[span.start as usize..span.end as usize].to_owned());
+                        continue;
+                    }
+                    1 => scope = name.is_dav("lockscope"),
+                    2 if scope => exclusive = !name.is_dav("shared"),
+                    _ => {}
+                }
+                if !empty {
+                    depth += 1;
+                }
+            }
+            Node::Close => depth = depth.saturating_sub(1),
+            Node::Eof => break,
+        }
+    }
+
+    Ok((exclusive, owner))
+}